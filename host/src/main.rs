@@ -7,7 +7,7 @@ use risc0_zkvm::{default_prover, ExecutorEnv, Receipt};
 use sha2::{Digest, Sha256};
 use tracing::info;
 
-use sparse_tree::{protocol::{ProofClaims, ProvingInput}, smt::SparseMerkleTree};
+use sparse_tree::{protocol::ProvingInput, smt::{HashAlgo, SparseMerkleTree}};
 
 fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt()
@@ -23,13 +23,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     let h = Sha256::new();
     let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
         SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
-    let pt = tree.batch_prove(&[0, 2, 10]);
-    let claim = ProofClaims { root: pt.root };
-    let input = ProvingInput {
-        pt,
-        claim
-    };
-    let env = ExecutorEnv::builder().write(&input).unwrap().build()?;
+    let input = ProvingInput::from_tree(&tree, &[0, 2, 10], HashAlgo::Sha256);
+    let env = ExecutorEnv::builder().write(&vec![input]).unwrap().build()?;
     let prover = default_prover();
 
     // Proof information by proving the specified ELF binary.
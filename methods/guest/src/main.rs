@@ -1,10 +1,31 @@
-use risc0_zkvm::guest::{self, env};
-use sha2::{Digest, Sha256};
-use sparse_tree::{protocol::{ProofClaims, ProvingInput}, smt::{PartialTree, BYTE32}};
+use risc0_zkvm::guest::env;
+#[cfg(not(feature = "poseidon"))]
+use sparse_tree::protocol::{verify_inputs, ProvingInput};
+#[cfg(feature = "poseidon")]
+use sparse_tree::{
+    poseidon::{Felt, Fp, Poseidon},
+    protocol::{run_verify, FieldInput},
+};
 
+#[cfg(not(feature = "poseidon"))]
 fn main() {
-    let p1: ProvingInput = env::read();
-    let h = Sha256::new();
-    p1.pt.verify(&h).expect("fail");   
-    env::commit(&p1.claim);
-}
\ No newline at end of file
+    let mut inputs: Vec<ProvingInput> = env::read();
+    // Keep in sync with protocol::journal_size.
+    verify_inputs(&mut inputs, |input| {
+        env::commit(&input.claim);
+        #[cfg(feature = "leaf-values")]
+        env::commit(&input.pt.leaf_values());
+    })
+    .expect("fail");
+}
+
+#[cfg(feature = "poseidon")]
+fn main() {
+    let mut inputs: Vec<FieldInput<Felt, 32>> = env::read();
+    run_verify(&mut inputs, &Poseidon::<Fp, 2>::new(), |input| {
+        env::commit(&input.root);
+        #[cfg(feature = "leaf-values")]
+        env::commit(&input.pt.leaf_values());
+    })
+    .expect("fail");
+}
@@ -0,0 +1,123 @@
+//! Navigation between node indices of a Sparse Merkle tree.
+//!
+//! Nodes are numbered breadth first from the root at `0`, so the children of
+//! node `i` are `2i + 1` and `2i + 2`, and the leaves of a tree of height `N`
+//! occupy `2^N - 1 ..= 2^(N+1) - 2`.
+//!
+//! ```text
+//!           0
+//!         /   \
+//!        1     2
+//!       / \   / \
+//!      3   4 5   6
+//! ```
+
+/// Returns the tree index of the leaf at `index` in a tree of the given
+/// height, which must be at most 63 for the index to fit in a `u64`.
+///
+/// The height is a runtime value here, so a taller one panics rather than
+/// failing to compile as the trees and paths of `smt` do.
+///
+/// ```
+/// use sparse_tree::index::convert_index_to_last_level;
+///
+/// assert_eq!(convert_index_to_last_level(0, 2), 3);
+/// assert_eq!(convert_index_to_last_level(3, 2), 6);
+/// ```
+#[inline]
+pub fn convert_index_to_last_level(index: u64, height: usize) -> u64 {
+    assert!(height <= 63, "the tree height must be at most 63");
+    index + (1u64 << height) - 1
+}
+
+/// Returns true iff the index represents the root.
+///
+/// ```
+/// use sparse_tree::index::is_root;
+///
+/// assert!(is_root(0));
+/// assert!(!is_root(1));
+/// ```
+#[inline]
+pub fn is_root(index: u64) -> bool {
+    index == 0
+}
+
+/// Returns the index of the left child, given an index.
+///
+/// ```
+/// use sparse_tree::index::left_child;
+///
+/// assert_eq!(left_child(0), 1);
+/// assert_eq!(left_child(2), 5);
+/// ```
+#[inline]
+pub fn left_child(index: u64) -> u64 {
+    2 * index + 1
+}
+
+/// Returns the index of the right child, given an index.
+///
+/// ```
+/// use sparse_tree::index::right_child;
+///
+/// assert_eq!(right_child(0), 2);
+/// assert_eq!(right_child(2), 6);
+/// ```
+#[inline]
+pub fn right_child(index: u64) -> u64 {
+    2 * index + 2
+}
+
+/// Returns the index of the sibling, given an index. The root has none.
+///
+/// ```
+/// use sparse_tree::index::sibling;
+///
+/// assert_eq!(sibling(3), Some(4));
+/// assert_eq!(sibling(4), Some(3));
+/// assert_eq!(sibling(0), None);
+/// ```
+#[inline]
+pub fn sibling(index: u64) -> Option<u64> {
+    if index == 0 {
+        None
+    } else if is_left_child(index) {
+        Some(index + 1)
+    } else {
+        Some(index - 1)
+    }
+}
+
+/// Returns true iff the given index represents a left child.
+///
+/// ```
+/// use sparse_tree::index::is_left_child;
+///
+/// assert!(is_left_child(5));
+/// assert!(!is_left_child(6));
+/// ```
+#[inline]
+pub fn is_left_child(index: u64) -> bool {
+    index % 2 == 1
+}
+
+/// Returns the index of the parent, given an index. The root has none.
+///
+/// ```
+/// use sparse_tree::index::{left_child, parent, right_child};
+///
+/// assert_eq!(parent(5), Some(2));
+/// assert_eq!(parent(6), Some(2));
+/// assert_eq!(parent(left_child(9)), Some(9));
+/// assert_eq!(parent(right_child(9)), Some(9));
+/// assert_eq!(parent(0), None);
+/// ```
+#[inline]
+pub fn parent(index: u64) -> Option<u64> {
+    if index > 0 {
+        Some((index - 1) >> 1)
+    } else {
+        None
+    }
+}
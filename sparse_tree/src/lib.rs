@@ -1,4 +1,8 @@
 #![allow(clippy::all)]
 
 pub mod smt;
-pub mod protocol;
\ No newline at end of file
+#[cfg(feature = "serde")]
+pub mod protocol;
+pub mod index;
+#[cfg(feature = "poseidon")]
+pub mod poseidon;
@@ -0,0 +1,466 @@
+//! Poseidon hashing for the Sparse Merkle tree.
+//!
+//! This module provides a Poseidon `FieldHasher` over prime fields and the
+//! `Felt` wrapper, which gives a field element the `FieldExt` bounds (notably
+//! serde, with the `serde` feature) so that Poseidon based trees, proofs and
+//! partial trees can be serialized and sent through the protocol.
+//!
+//! Field elements are serialized as their canonical 32-byte representation.
+//! Deserialization rejects encodings that are not canonical.
+//!
+//! With the `bn254` feature, the `bn254` module adds the BN254 scalar field
+//! and a Poseidon hasher laid out like circomlib's, whose roots EVM verifiers
+//! accept.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{bail, Result};
+use ff::{FromUniformBytes, PrimeField};
+use halo2_poseidon::{generate_constants, ConstantLength, Hash, Mds, Spec};
+#[cfg(feature = "serde")]
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+pub use pasta_curves::Fp;
+
+use crate::smt::{FieldExt, FieldHasher, BYTE32};
+
+/// Poseidon-128 using the x^5 S-box, with 8 full rounds and `PARTIAL_ROUNDS`
+/// partial rounds.
+///
+/// Unlike the hard-coded orchard spec, the constants are generated for any
+/// field, so the same spec can be used over other scalar fields. Use the
+/// aliases below, which carry the standard round numbers for their width.
+#[derive(Debug, Clone, Copy)]
+pub struct SmtP128Pow5<
+    F: PrimeField,
+    const WIDTH: usize,
+    const RATE: usize,
+    const PARTIAL_ROUNDS: usize,
+    const SECURE_MDS: usize,
+>(PhantomData<F>);
+
+/// Width 3 and rate 2, `R_F = 8, R_P = 56`. Hashes two nodes.
+pub type SmtP128Pow5T3<F, const SECURE_MDS: usize> = SmtP128Pow5<F, 3, 2, 56, SECURE_MDS>;
+
+/// Width 5 and rate 4, `R_F = 8, R_P = 60`. Hashes four nodes.
+pub type SmtP128Pow5T5<F, const SECURE_MDS: usize> = SmtP128Pow5<F, 5, 4, 60, SECURE_MDS>;
+
+/// Width 3 and rate 2 with `R_P = 57`, the round numbers circomlib uses over
+/// BN254 for two inputs.
+pub type SmtP128Pow5T3Circom<F> = SmtP128Pow5<F, 3, 2, 57, 0>;
+
+impl<
+        F: PrimeField + FromUniformBytes<64> + Ord,
+        const WIDTH: usize,
+        const RATE: usize,
+        const PARTIAL_ROUNDS: usize,
+        const SECURE_MDS: usize,
+    > Spec<F, WIDTH, RATE> for SmtP128Pow5<F, WIDTH, RATE, PARTIAL_ROUNDS, SECURE_MDS>
+{
+    fn full_rounds() -> usize {
+        8
+    }
+
+    fn partial_rounds() -> usize {
+        PARTIAL_ROUNDS
+    }
+
+    fn sbox(val: F) -> F {
+        val.pow_vartime([5])
+    }
+
+    fn secure_mds() -> usize {
+        SECURE_MDS
+    }
+
+    fn constants() -> (Vec<[F; WIDTH]>, Mds<F, WIDTH>, Mds<F, WIDTH>) {
+        Self::cached_constants().clone()
+    }
+}
+
+/// The round constants and MDS matrices of a spec.
+type Constants<F, const WIDTH: usize> = (Vec<[F; WIDTH]>, Mds<F, WIDTH>, Mds<F, WIDTH>);
+
+impl<
+        F: PrimeField + FromUniformBytes<64> + Ord,
+        const WIDTH: usize,
+        const RATE: usize,
+        const PARTIAL_ROUNDS: usize,
+        const SECURE_MDS: usize,
+    > SmtP128Pow5<F, WIDTH, RATE, PARTIAL_ROUNDS, SECURE_MDS>
+{
+    /// Returns the constants of the spec, generated on the first call only:
+    /// generating them takes far longer than a hash.
+    fn cached_constants() -> &'static Constants<F, WIDTH> {
+        cached::<Self, _>(generate_constants::<F, Self, WIDTH, RATE>)
+    }
+}
+
+/// Returns the value `init` computes for the type `K`, calling `init` the first
+/// time only. Statics can't be generic, so values are keyed by type.
+fn cached<K: 'static, V: Send + Sync + 'static>(init: impl FnOnce() -> V) -> &'static V {
+    type Cache = Mutex<HashMap<TypeId, &'static (dyn Any + Send + Sync)>>;
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+
+    let cache = CACHE.get_or_init(Default::default);
+    let key = TypeId::of::<K>();
+    let found = cache.lock().unwrap_or_else(|e| e.into_inner()).get(&key).copied();
+    let value = match found {
+        Some(value) => value,
+        None => {
+            // Generated without the lock held, a racing thread may store its
+            // value first. Both are equal.
+            let value: &'static (dyn Any + Send + Sync) = Box::leak(Box::new(init()));
+            *cache.lock().unwrap_or_else(|e| e.into_inner()).entry(key).or_insert(value)
+        }
+    };
+
+    value.downcast_ref().expect("cached values are keyed by type")
+}
+
+/// Poseidon hash of `L` field elements.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Poseidon<F, const L: usize> {
+    marker: PhantomData<F>,
+}
+
+impl<F, const L: usize> Poseidon<F, L> {
+    pub fn new() -> Self {
+        Poseidon {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<F: PrimeField + FromUniformBytes<64> + Ord> FieldHasher<F, 2> for Poseidon<F, 2> {
+    fn hash(&self, nodes: [F; 2]) -> Result<F> {
+        Ok(Hash::<F, SmtP128Pow5T3<F, 0>, ConstantLength<2>, 3, 2>::init().hash(nodes))
+    }
+}
+
+impl<F: PrimeField + FromUniformBytes<64> + Ord> FieldHasher<Felt<F>, 2> for Poseidon<F, 2> {
+    fn hash(&self, nodes: [Felt<F>; 2]) -> Result<Felt<F>> {
+        FieldHasher::<F, 2>::hash(self, nodes.map(|n| n.0)).map(Felt)
+    }
+}
+
+impl<F: PrimeField + FromUniformBytes<64> + Ord> FieldHasher<F, 4> for Poseidon<F, 4> {
+    fn hash(&self, nodes: [F; 4]) -> Result<F> {
+        Ok(Hash::<F, SmtP128Pow5T5<F, 0>, ConstantLength<4>, 5, 4>::init().hash(nodes))
+    }
+}
+
+impl<F: PrimeField + FromUniformBytes<64> + Ord> FieldHasher<Felt<F>, 4> for Poseidon<F, 4> {
+    fn hash(&self, nodes: [Felt<F>; 4]) -> Result<Felt<F>> {
+        FieldHasher::<F, 4>::hash(self, nodes.map(|n| n.0)).map(Felt)
+    }
+}
+
+/// Runs the Poseidon permutation of `S` on `state`.
+#[cfg(feature = "bn254")]
+fn permute<F: PrimeField, S: Spec<F, T, RATE>, const T: usize, const RATE: usize>(
+    state: &mut [F; T],
+    round_constants: &[[F; T]],
+    mds: &Mds<F, T>,
+) {
+    let half_full = S::full_rounds() / 2;
+    for (round, rcs) in round_constants.iter().enumerate() {
+        let full = round < half_full || round >= half_full + S::partial_rounds();
+        for (i, word) in state.iter_mut().enumerate() {
+            *word += rcs[i];
+            if full || i == 0 {
+                *word = S::sbox(*word);
+            }
+        }
+        let mut mixed = [F::ZERO; T];
+        for (i, row) in mds.iter().enumerate() {
+            for (j, m) in row.iter().enumerate() {
+                mixed[i] += *m * state[j];
+            }
+        }
+        *state = mixed;
+    }
+}
+
+/// Encodes `f` as its canonical 32-byte little-endian representation, the
+/// form `BYTE32` based trees and claims carry Pasta elements in.
+pub fn felt_to_bytes(f: &Fp) -> BYTE32 {
+    f.to_repr()
+}
+
+/// Decodes a little-endian field element, failing if `b` is not the canonical
+/// encoding of an element, i.e. if it is not below the modulus.
+pub fn bytes_to_felt(b: &BYTE32) -> Result<Fp> {
+    match Option::from(Fp::from_repr(*b)) {
+        Some(f) => Ok(f),
+        None => bail!("non-canonical field element"),
+    }
+}
+
+/// A field element usable as a tree node.
+///
+/// Serialized as the canonical 32-byte representation of the element.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Felt<F: PrimeField = Fp>(pub F);
+
+impl<F: PrimeField> FieldExt for Felt<F> {}
+
+impl<F: PrimeField> From<F> for Felt<F> {
+    fn from(f: F) -> Self {
+        Felt(f)
+    }
+}
+
+impl<F: PrimeField> fmt::Debug for Felt<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Felt({:?})", self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<F: PrimeField> Serialize for Felt<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = self.0.to_repr();
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(repr.as_ref());
+        bytes.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, F: PrimeField> Deserialize<'de> for Felt<F> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = <[u8; 32]>::deserialize(deserializer)?;
+        let mut repr = F::Repr::default();
+        if repr.as_ref().len() != bytes.len() {
+            return Err(D::Error::custom("field representation is not 32 bytes"));
+        }
+        repr.as_mut().copy_from_slice(&bytes);
+        Option::from(F::from_repr(repr))
+            .map(Felt)
+            .ok_or_else(|| D::Error::custom("non-canonical field element"))
+    }
+}
+
+/// The BN254 scalar field, and Poseidon as circomlib computes it over it.
+#[cfg(feature = "bn254")]
+pub mod bn254 {
+    use ff::{Field, FromUniformBytes, PrimeField};
+    use super::{permute, Felt, SmtP128Pow5T3Circom};
+    use crate::smt::FieldHasher;
+    use anyhow::Result;
+
+    /// The scalar field of BN254, also known as alt_bn128.
+    #[derive(PrimeField)]
+    #[PrimeFieldModulus = "21888242871839275222246405745257275088548364400416034343698204186575808495617"]
+    #[PrimeFieldGenerator = "7"]
+    #[PrimeFieldReprEndianness = "little"]
+    pub struct Fr([u64; 4]);
+
+    /// Reduces the 512-bit little-endian integer `bytes` modulo the field.
+    impl FromUniformBytes<64> for Fr {
+        fn from_uniform_bytes(bytes: &[u8; 64]) -> Self {
+            let limb = Fr::from(u64::MAX) + Fr::ONE;
+            bytes.rchunks_exact(8).fold(Fr::ZERO, |acc, chunk| {
+                let mut word = [0; 8];
+                word.copy_from_slice(chunk);
+                acc * limb + Fr::from(u64::from_le_bytes(word))
+            })
+        }
+    }
+
+    /// Poseidon over two inputs as circomlib's `Poseidon(2)` and the
+    /// matching Solidity contracts compute it: the state starts as
+    /// `[0, left, right]` and the hash is the first word after the
+    /// permutation.
+    ///
+    /// This differs from `Poseidon<Fr, 2>`, which follows the halo2 sponge
+    /// with the capacity word last.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct CircomPoseidon;
+
+    impl FieldHasher<Fr, 2> for CircomPoseidon {
+        fn hash(&self, nodes: [Fr; 2]) -> Result<Fr> {
+            let (round_constants, mds, _) = SmtP128Pow5T3Circom::<Fr>::cached_constants();
+            let mut state = [Fr::ZERO, nodes[0], nodes[1]];
+            permute::<Fr, SmtP128Pow5T3Circom<Fr>, 3, 2>(&mut state, round_constants, mds);
+            Ok(state[0])
+        }
+    }
+
+    impl FieldHasher<Felt<Fr>, 2> for CircomPoseidon {
+        fn hash(&self, nodes: [Felt<Fr>; 2]) -> Result<Felt<Fr>> {
+            FieldHasher::<Fr, 2>::hash(self, nodes.map(|n| n.0)).map(Felt)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::smt::SparseMerkleTree;
+    use halo2_poseidon::P128Pow5T3;
+
+    type PoseidonTree = SparseMerkleTree<Felt, Poseidon<Fp, 2>, 8>;
+
+    fn leaves() -> Vec<Felt> {
+        (0..5u64).map(|n| Felt(Fp::from(n + 1))).collect()
+    }
+
+    #[test]
+    fn orchard_spec_equivalence() {
+        let message = [Fp::from(6), Fp::from(42)];
+
+        let expected = Hash::<_, P128Pow5T3, ConstantLength<2>, 3, 2>::init().hash(message);
+        let got = FieldHasher::<Fp, 2>::hash(&Poseidon::<Fp, 2>::new(), message).unwrap();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn constants_generated_once() {
+        let first = SmtP128Pow5T3::<Fp, 0>::cached_constants();
+        assert!(std::ptr::eq(first, SmtP128Pow5T3::<Fp, 0>::cached_constants()));
+        assert_eq!(first.0, generate_constants::<Fp, SmtP128Pow5T3<Fp, 0>, 3, 2>().0);
+        // Another spec over the same field gets its own constants.
+        assert_eq!(SmtP128Pow5T5::<Fp, 0>::cached_constants().0.len(), 8 + 60);
+        assert_eq!(SmtP128Pow5T3::<Fp, 0>::cached_constants().0.len(), 8 + 56);
+    }
+
+    #[test]
+    fn width5_permutation() {
+        use ff::Field;
+
+        // Reference permutation, run directly on the generated constants.
+        let (round_constants, mds, _) = SmtP128Pow5T5::<Fp, 0>::constants();
+        assert_eq!(round_constants.len(), 8 + 60);
+        let message = [Fp::from(1), Fp::from(2), Fp::from(3), Fp::from(4)];
+        let mut state = [message[0], message[1], message[2], message[3], Fp::from_u128(4 << 64)];
+        for (round, rcs) in round_constants.iter().enumerate() {
+            let full = round < 4 || round >= 4 + 60;
+            for (i, word) in state.iter_mut().enumerate() {
+                *word += rcs[i];
+                if full || i == 0 {
+                    *word = word.pow_vartime([5]);
+                }
+            }
+            let mut mixed = [Fp::from(0); 5];
+            for (i, row) in mds.iter().enumerate() {
+                for (j, m) in row.iter().enumerate() {
+                    mixed[i] += *m * state[j];
+                }
+            }
+            state = mixed;
+        }
+
+        let got = FieldHasher::<Fp, 4>::hash(&Poseidon::<Fp, 4>::new(), message).unwrap();
+        assert_eq!(state[0], got);
+        let felt = FieldHasher::<Felt, 4>::hash(&Poseidon::<Fp, 4>::new(), message.map(Felt));
+        assert_eq!(felt.unwrap(), Felt(got));
+    }
+
+    #[test]
+    #[cfg(feature = "bn254")]
+    fn bn254_spec_equivalence() {
+        use bn254::Fr;
+
+        // The halo2 sponge over BN254, against the permutation run directly
+        // on `[message, capacity]`.
+        let message = [Fr::from(6), Fr::from(42)];
+        let (round_constants, mds, _) = SmtP128Pow5T3::<Fr, 0>::constants();
+        let mut state = [message[0], message[1], Fr::from_u128(2 << 64)];
+        permute::<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2>(&mut state, &round_constants, &mds);
+        let got = FieldHasher::<Fr, 2>::hash(&Poseidon::<Fr, 2>::new(), message).unwrap();
+        assert_eq!(state[0], got);
+    }
+
+    #[test]
+    #[cfg(feature = "bn254")]
+    fn circom_poseidon_vector() {
+        use bn254::{CircomPoseidon, Fr};
+
+        // circomlibjs `poseidon([1, 2])`.
+        let expected = Fr::from_str_vartime(
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+        )
+        .unwrap();
+        let got = FieldHasher::<Fr, 2>::hash(&CircomPoseidon, [Fr::from(1), Fr::from(2)]);
+        assert_eq!(got.unwrap(), expected);
+
+        let h = CircomPoseidon;
+        let leaves: Vec<Felt<Fr>> = (1..5u64).map(|n| Felt(Fr::from(n))).collect();
+        let tree: SparseMerkleTree<Felt<Fr>, CircomPoseidon, 8> =
+            SparseMerkleTree::new_sequential(&leaves, &h, Felt::default()).unwrap();
+        assert!(tree.generate_membership_proof(2).unwrap().verify(&h).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn felt_serde_roundtrip() {
+        use crate::smt::{PartialTree, Proof};
+
+        let f = Felt(Fp::from(0xdead_beef));
+        let s = serde_json::to_string(&f).unwrap();
+        let back: Felt = serde_json::from_str(&s).unwrap();
+        assert_eq!(f, back);
+
+        let h = Poseidon::<Fp, 2>::new();
+        let tree = PoseidonTree::new_sequential(&leaves(), &h, Felt::default()).unwrap();
+
+        let proof = tree.generate_membership_proof(3).unwrap();
+        let s = serde_json::to_string(&proof).unwrap();
+        let back: Proof<Felt, 8> = serde_json::from_str(&s).unwrap();
+        assert!(back.verify(&h).unwrap());
+
+        let pt = tree.batch_prove(&[0, 3]);
+        let s = serde_json::to_string(&pt).unwrap();
+        let back: PartialTree<Felt, 8> = serde_json::from_str(&s).unwrap();
+        assert_eq!(back.root, tree.root());
+        assert_eq!(back.tree, pt.tree);
+        back.verify(&h).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn felt_rejects_non_canonical() {
+        let s = serde_json::to_string(&[0xffu8; 32]).unwrap();
+        assert!(serde_json::from_str::<Felt>(&s).is_err());
+    }
+
+    #[test]
+    fn felt_bytes_roundtrip() {
+        for f in [Fp::from(0), Fp::from(1), Fp::from(0xdead_beef), -Fp::from(1)] {
+            assert_eq!(bytes_to_felt(&felt_to_bytes(&f)).unwrap(), f);
+        }
+        let mut one = [0; 32];
+        one[0] = 1;
+        assert_eq!(felt_to_bytes(&Fp::from(1)), one);
+
+        // p - 1 is the largest canonical encoding, p itself is rejected.
+        let mut modulus = felt_to_bytes(&-Fp::from(1));
+        modulus[0] += 1;
+        assert!(bytes_to_felt(&modulus).is_err());
+        assert!(bytes_to_felt(&[0xff; 32]).is_err());
+    }
+
+    #[test]
+    fn poseidon_membership() {
+        let h = Poseidon::<Fp, 2>::new();
+        let leaves = leaves();
+        let tree = PoseidonTree::new_sequential(&leaves, &h, Felt::default()).unwrap();
+
+        for (i, leaf) in leaves.iter().enumerate() {
+            let path = tree.generate_membership_path(i as u64).unwrap();
+            assert!(path.check_membership(&tree.root(), leaf, &h).unwrap());
+        }
+        let path = tree.generate_membership_path(1).unwrap();
+        assert!(!path
+            .check_membership(&tree.root(), &Felt(Fp::from(99)), &h)
+            .unwrap_or(false));
+    }
+}
@@ -1,14 +1,553 @@
+use std::fmt::Debug;
+
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::smt::{PartialTree, BYTE32};
+use crate::smt::{
+    BytesHasher, DynHasher, EmptyRegion, FieldExt, FieldHasher, HashAlgo, PartialTree,
+    SparseMerkleTree, BYTE32,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProofClaims {
     pub root: BYTE32,
+    /// The hash function the tree was built with.
+    pub hash: HashAlgo,
+    /// The value of unset leaves, from which the tree's empty hashes derive.
+    pub empty_leaf: BYTE32,
+    /// The region of the tree with its own empty leaf, if any. Like the empty
+    /// leaf, it decides what the absent leaves hold.
+    pub region: Option<EmptyRegion<BYTE32>>,
+    /// The number of proven leaves, so that a verifier can check it without
+    /// reading the leaves.
+    pub leaf_count: u32,
+    /// The values the proven leaves may hold. Empty allows any value.
+    pub allowed_values: Vec<BYTE32>,
+    /// Entry `k` is the `key_commitment` of the application key of the `k`th
+    /// proven leaf, in ascending index order. Empty commits to no keys.
+    pub key_commitments: Vec<BYTE32>,
+}
+
+/// Returns the hash, with `hash`, binding the application key `key` to map
+/// index `index`: `key` followed by `index` as 8 little-endian bytes.
+///
+/// A verifier that knows the key it expects at a proven index recomputes this
+/// and looks for it in `ProofClaims::key_commitments`.
+pub fn key_commitment(hash: HashAlgo, key: &[u8], index: u64) -> Result<BYTE32> {
+    DynHasher::new(hash).hash_bytes(&[key, &index.to_le_bytes()].concat())
+}
+
+/// A secret whose hash is stored at a proven leaf. It stays in the private
+/// input, the guest only checks it against the leaf.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Preimage {
+    /// The map index of the leaf.
+    pub index: u64,
+    pub secret: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProvingInput {
     pub pt: PartialTree<BYTE32, 32>,
-    pub claim: ProofClaims
-}
\ No newline at end of file
+    pub claim: ProofClaims,
+    /// Openings of proven leaves that commit to a secret.
+    pub preimages: Vec<Preimage>,
+    /// The application key of each proven leaf, as in
+    /// `ProofClaims::key_commitments`. Like the preimages, they stay private.
+    pub keys: Vec<Vec<u8>>,
+}
+
+impl ProvingInput {
+    /// Proves `leaves` of `tree`, and claims the tree's root, empty leaf,
+    /// empty region and leaf count. `hash` names the function `tree` was built with, it can't
+    /// be told from the hasher type.
+    pub fn from_tree<H: FieldHasher<BYTE32, 2>>(
+        tree: &SparseMerkleTree<BYTE32, H, 32>,
+        leaves: &[u64],
+        hash: HashAlgo,
+    ) -> ProvingInput {
+        let pt = tree.batch_prove(leaves);
+        let claim = ProofClaims {
+            root: tree.root(),
+            hash,
+            empty_leaf: tree.empty_hashes()[0],
+            region: tree.empty_region().cloned(),
+            leaf_count: pt.leaves.len() as u32,
+            allowed_values: vec![],
+            key_commitments: vec![],
+        };
+
+        ProvingInput {
+            pt,
+            claim,
+            preimages: vec![],
+            keys: vec![],
+        }
+    }
+
+    /// Sets the application key of each proven leaf, in ascending index
+    /// order, and claims their `key_commitment`s.
+    pub fn commit_keys(&mut self, keys: Vec<Vec<u8>>) -> Result<()> {
+        self.pt.canonicalize();
+        self.claim.key_commitments = self.key_commitments(&keys)?;
+        self.keys = keys;
+
+        Ok(())
+    }
+
+    /// The commitment of each of `keys` to the proven leaf at its position.
+    /// Expects canonical leaves.
+    fn key_commitments(&self, keys: &[Vec<u8>]) -> Result<Vec<BYTE32>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        if keys.len() != self.pt.leaves.len() {
+            bail!("{} keys given for {} proven leaves", keys.len(), self.pt.leaves.len());
+        }
+        keys.iter()
+            .zip(&self.pt.leaves)
+            .map(|(key, index)| key_commitment(self.claim.hash, key, *index))
+            .collect()
+    }
+
+    /// Verifies the partial tree against the claimed root with the claimed
+    /// hash function, and checks that the claim describes the partial tree.
+    /// The claim is what the guest commits, so the journal carries the root
+    /// the tree was checked against.
+    pub fn verify(&self) -> Result<()> {
+        let h = DynHasher::new(self.claim.hash);
+        self.pt.verify_against(&self.claim.root, &h)?;
+        if self.pt.empty_hashes().first() != Some(&self.claim.empty_leaf) {
+            bail!("claimed empty leaf does not match the partial tree");
+        }
+        if self.pt.region != self.claim.region {
+            bail!("claimed empty region does not match the partial tree");
+        }
+        if self.claim.leaf_count as usize != self.pt.leaves.len() {
+            bail!("claimed leaf count does not match the partial tree");
+        }
+
+        let leaves = self.pt.leaf_values();
+        if !self.claim.allowed_values.is_empty() {
+            if let Some((i, _)) = leaves
+                .iter()
+                .find(|(_, leaf)| !self.claim.allowed_values.contains(leaf))
+            {
+                bail!("leaf {} holds a value that is not allowed", i);
+            }
+        }
+        for preimage in &self.preimages {
+            let leaf = match leaves.iter().find(|(i, _)| *i == preimage.index) {
+                Some((_, leaf)) => leaf,
+                None => bail!("leaf {} is not proven", preimage.index),
+            };
+            if *leaf != h.hash_bytes(&preimage.secret)? {
+                bail!("leaf {} is not the hash of its preimage", preimage.index);
+            }
+        }
+        if self.key_commitments(&self.keys)? != self.claim.key_commitments {
+            bail!("claimed key commitments do not match the keys");
+        }
+
+        Ok(())
+    }
+}
+
+/// Verifies each input in order, and passes it to `commit` once verified.
+///
+/// This is the guest's main loop, `commit` writes to the journal. The leaf
+/// lists of each partial tree are sorted and deduped first, so that the
+/// journal doesn't depend on the order the prover listed the leaves in.
+pub fn verify_inputs(
+    inputs: &mut [ProvingInput],
+    mut commit: impl FnMut(&ProvingInput),
+) -> Result<()> {
+    for (i, input) in inputs.iter_mut().enumerate() {
+        input.pt.canonicalize();
+        input
+            .verify()
+            .map_err(|e| e.context(format!("proving input {} failed to verify", i)))?;
+        commit(input);
+    }
+
+    Ok(())
+}
+
+/// A partial tree over any element type and the root it is claimed to have,
+/// the input of `run_verify`. Unlike `ProvingInput`, the hasher is fixed by
+/// the guest rather than claimed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FieldInput<F: FieldExt, const N: usize> {
+    pub pt: PartialTree<F, N>,
+    pub root: F,
+}
+
+/// Like `verify_inputs`, for trees of any element type and height hashed with
+/// `hasher`: each partial tree is canonicalized, verified against its root,
+/// and passed to `commit`.
+///
+/// Only the root is trusted, so partial trees with an empty region are
+/// rejected: nothing would bind the region the absent leaves are checked
+/// against.
+///
+/// A guest picks `F`, `H` and `N`, e.g. Poseidon over `Felt` with its
+/// `poseidon` feature.
+pub fn run_verify<F: FieldExt + Debug, H: FieldHasher<F, 2>, const N: usize>(
+    inputs: &mut [FieldInput<F, N>],
+    hasher: &H,
+    mut commit: impl FnMut(&FieldInput<F, N>),
+) -> Result<()> {
+    for (i, input) in inputs.iter_mut().enumerate() {
+        input.pt.canonicalize();
+        if input.pt.region.is_some() {
+            bail!("proving input {} has an empty region", i);
+        }
+        input
+            .pt
+            .verify_against(&input.root, hasher)
+            .map_err(|e| e.context(format!("proving input {} failed to verify", i)))?;
+        commit(input);
+    }
+
+    Ok(())
+}
+
+/// The journal is the risc0 serde encoding, which writes each integer as
+/// little-endian 32-bit words. A byte takes a word of its own, a `u64` two,
+/// and lengths and enum variants one each.
+const WORD: usize = 4;
+
+/// Returns the number of bytes the guest commits for one input, in the order
+/// it commits them: the `ProofClaims`, then with the guest's `leaf-values`
+/// feature the `(index, value)` pair of each of the `leaf_count` proven
+/// leaves. `allowed_values` and `key_commitments` are the lengths of those
+/// `ProofClaims` fields, and `region` whether it claims an empty region.
+///
+/// A journal holds one such record per input, see `verify_inputs`.
+pub const fn journal_size(
+    leaf_count: usize,
+    allowed_values: usize,
+    key_commitments: usize,
+    region: bool,
+    leaf_values: bool,
+) -> usize {
+    // An option tag, then start, end and empty_leaf.
+    let region = if region { 1 + 2 + 2 + 32 } else { 1 };
+    // root, hash, empty_leaf, region, leaf_count, allowed_values,
+    // key_commitments
+    let claim =
+        32 + 1 + 32 + region + 1 + (1 + 32 * allowed_values) + (1 + 32 * key_commitments);
+    let leaves = if leaf_values { 1 + leaf_count * (2 + 32) } else { 0 };
+
+    WORD * (claim + leaves)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use sha2::{Digest, Sha256};
+
+    fn input(tree: &SparseMerkleTree<BYTE32, DynHasher, 32>, leaves: &[u64]) -> ProvingInput {
+        ProvingInput::from_tree(tree, leaves, HashAlgo::Sha256)
+    }
+
+    #[test]
+    fn claim_binds_empty_leaf() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [7; 32]).unwrap();
+
+        let pt = tree.batch_prove(&[1, 20]);
+        let claim = ProofClaims {
+            root: pt.root,
+            hash: HashAlgo::Sha256,
+            empty_leaf: [7; 32],
+            region: None,
+            leaf_count: 2,
+            allowed_values: vec![],
+            key_commitments: vec![],
+        };
+        let mut input = ProvingInput {
+            pt,
+            claim,
+            preimages: vec![],
+            keys: vec![],
+        };
+        input.verify().unwrap();
+
+        input.claim.empty_leaf = [0; 32];
+        assert!(input.verify().is_err());
+    }
+
+    #[test]
+    fn claim_binds_region() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let region = EmptyRegion {
+            start: 4,
+            end: 8,
+            empty_leaf: [6; 32],
+        };
+
+        // Leaf 5 holds [6; 32]. A forged region passes it off as empty, and
+        // the root still matches, as every sibling is stored.
+        let mut forged = input(&tree, &[1]);
+        forged.pt = tree.batch_prove_with_absent(&[1], &[5]);
+        assert!(forged.verify().is_err());
+        forged.pt.region = Some(region);
+        let err = forged.verify().unwrap_err();
+        assert_eq!(err.to_string(), "claimed empty region does not match the partial tree");
+
+        let mut inputs = vec![FieldInput {
+            pt: forged.pt,
+            root: tree.root(),
+        }];
+        let err = run_verify(&mut inputs, &h, |_| {}).unwrap_err();
+        assert_eq!(err.to_string(), "proving input 0 has an empty region");
+    }
+
+    #[test]
+    fn commits_each_input_in_order() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let a: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&[[1; 32], [2; 32]], &h, [0; 32]).unwrap();
+        let b: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&[[3; 32], [4; 32]], &h, [0; 32]).unwrap();
+        let mut inputs = vec![input(&a, &[0]), input(&b, &[1])];
+
+        let mut committed = vec![];
+        verify_inputs(&mut inputs, |input| committed.push(input.claim.root)).unwrap();
+        assert_eq!(committed, vec![a.root(), b.root()]);
+
+        inputs[1].claim.root = [0; 32];
+        let mut committed = vec![];
+        let err = verify_inputs(&mut inputs, |input| committed.push(input.claim.root)).unwrap_err();
+        assert_eq!(err.to_string(), "proving input 1 failed to verify");
+        assert_eq!(committed, vec![a.root()]);
+    }
+
+    #[test]
+    fn checks_preimages() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let secret = b"secret".to_vec();
+        let leaves = [[1; 32], h.hash_bytes(&secret).unwrap()];
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let mut input = input(&tree, &[0, 1]);
+        input.preimages.push(Preimage { index: 1, secret });
+        input.verify().unwrap();
+
+        input.preimages[0].secret = b"guess".to_vec();
+        assert!(input.verify().is_err());
+
+        input.preimages[0] = Preimage {
+            index: 5,
+            secret: b"secret".to_vec(),
+        };
+        assert!(input.verify().is_err());
+    }
+
+    #[test]
+    fn claim_binds_leaf_count() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let mut inputs = vec![input(&tree, &[0, 3, 3, 8])];
+        let mut committed = vec![];
+        verify_inputs(&mut inputs, |input| committed.push(input.claim.leaf_count)).unwrap();
+        assert_eq!(committed, vec![3]);
+
+        inputs[0].claim.leaf_count = 4;
+        assert!(verify_inputs(&mut inputs, |_| {}).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "notzk")]
+    fn journal_is_order_independent() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let sorted = input(&tree, &[1, 4, 7]);
+        let mut shuffled = input(&tree, &[1, 4, 7]);
+        shuffled.pt.leaves = vec![7, 1, 4, 1];
+        assert_ne!(sorted.pt.to_bytes(), shuffled.pt.to_bytes());
+
+        let mut journals = vec![];
+        verify_inputs(&mut [sorted, shuffled], |input| {
+            // As the guest commits.
+            let mut journal = risc0_zkvm::serde::to_vec(&input.claim).unwrap();
+            journal.extend(risc0_zkvm::serde::to_vec(&input.pt.leaf_values()).unwrap());
+            journals.push(journal);
+        })
+        .unwrap();
+        assert_eq!(journals[0], journals[1]);
+    }
+
+    #[test]
+    fn run_verify_sha256() {
+        let h = Sha256::new();
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let mut inputs = vec![
+            FieldInput {
+                pt: tree.batch_prove(&[4, 1]),
+                root: tree.root(),
+            },
+            FieldInput {
+                pt: tree.batch_prove(&[8]),
+                root: tree.root(),
+            },
+        ];
+        let mut committed = vec![];
+        run_verify(&mut inputs, &h, |input| committed.push(input.pt.leaf_values())).unwrap();
+        assert_eq!(committed, vec![vec![(1, [2; 32]), (4, [5; 32])], vec![(8, [9; 32])]]);
+
+        inputs[1].root = [0; 32];
+        let err = run_verify(&mut inputs, &h, |_| {}).unwrap_err();
+        assert_eq!(err.to_string(), "proving input 1 failed to verify");
+    }
+
+    #[test]
+    #[cfg(feature = "poseidon")]
+    fn run_verify_poseidon() {
+        use crate::poseidon::{Felt, Fp, Poseidon};
+
+        let h = Poseidon::<Fp, 2>::new();
+        let leaves: Vec<Felt> = (1..10).map(|n| Felt(Fp::from(n))).collect();
+        let tree: SparseMerkleTree<Felt, Poseidon<Fp, 2>, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, Felt(Fp::from(0))).unwrap();
+
+        let mut inputs = vec![FieldInput {
+            pt: tree.batch_prove(&[2, 7]),
+            root: tree.root(),
+        }];
+        let mut committed = vec![];
+        run_verify(&mut inputs, &h, |input| committed.push(input.root)).unwrap();
+        assert_eq!(committed, vec![tree.root()]);
+
+        inputs[0].root = Felt(Fp::from(1));
+        assert!(run_verify(&mut inputs, &h, |_| {}).is_err());
+    }
+
+    #[test]
+    fn input_from_tree() {
+        let h = Sha256::new();
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let input = ProvingInput::from_tree(&tree, &[2, 7, 30], HashAlgo::Sha256);
+        input.pt.verify(&h).unwrap();
+        assert_eq!(input.claim.root, tree.root());
+        assert_eq!(input.claim.leaf_count, 3);
+        input.verify().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "notzk")]
+    fn journal_layout() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let region = EmptyRegion {
+            start: 16,
+            end: 32,
+            empty_leaf: [5; 32],
+        };
+        let map = BTreeMap::from([(20, [1; 32])]);
+        let regional: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::with_empty_region(&map, &h, [0; 32], region).unwrap();
+        let mut inputs = vec![input(&tree, &[]), input(&tree, &[1, 4, 7]), input(&regional, &[20])];
+        inputs[1].claim.allowed_values = leaves.clone();
+        inputs[1].commit_keys(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]).unwrap();
+        for leaf_values in [false, true] {
+            let mut sizes = vec![];
+            verify_inputs(&mut inputs, |input| {
+                // As the guest commits, word by word.
+                let mut words = risc0_zkvm::serde::to_vec(&input.claim).unwrap();
+                if leaf_values {
+                    words.extend(risc0_zkvm::serde::to_vec(&input.pt.leaf_values()).unwrap());
+                }
+                let journal: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+                sizes.push(journal.len());
+            })
+            .unwrap();
+            assert_eq!(sizes[0], journal_size(0, 0, 0, false, leaf_values));
+            assert_eq!(sizes[1], journal_size(3, 9, 3, false, leaf_values));
+            assert_eq!(sizes[2], journal_size(1, 0, 0, true, leaf_values));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "notzk")]
+    fn commits_key_commitments() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let mut keyed = input(&tree, &[6, 2]);
+        let keys = vec![b"alice".to_vec(), b"bob".to_vec()];
+        keyed.commit_keys(keys.clone()).unwrap();
+        let mut journals = vec![];
+        verify_inputs(&mut [keyed], |input| {
+            journals.push(risc0_zkvm::serde::to_vec(&input.claim).unwrap());
+        })
+        .unwrap();
+
+        // The verifier only sees the journal, and expects alice at 2.
+        let claim: ProofClaims = risc0_zkvm::serde::from_slice(&journals[0]).unwrap();
+        let alice = key_commitment(HashAlgo::Sha256, b"alice", 2).unwrap();
+        assert_eq!(claim.key_commitments[0], alice);
+        assert_eq!(claim.key_commitments[1], key_commitment(claim.hash, b"bob", 6).unwrap());
+        assert_ne!(alice, key_commitment(HashAlgo::Sha256, b"alice", 6).unwrap());
+
+        let mut swapped = input(&tree, &[2, 6]);
+        swapped.commit_keys(keys).unwrap();
+        swapped.keys.reverse();
+        let err = swapped.verify().unwrap_err();
+        assert_eq!(err.to_string(), "claimed key commitments do not match the keys");
+
+        let mut forged = input(&tree, &[2, 6]);
+        forged.claim.key_commitments = vec![alice, alice];
+        assert!(forged.verify().is_err());
+        assert!(input(&tree, &[2, 6]).commit_keys(vec![b"alice".to_vec()]).is_err());
+    }
+
+    #[test]
+    fn checks_allowed_values() {
+        let h = DynHasher::new(HashAlgo::Sha256);
+        let leaves: Vec<BYTE32> = vec![[1; 32], [2; 32], [3; 32], [1; 32]];
+        let tree: SparseMerkleTree<BYTE32, DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let mut inside = input(&tree, &[0, 1, 3]);
+        inside.claim.allowed_values = vec![[1; 32], [2; 32]];
+        inside.verify().unwrap();
+
+        let mut outside = input(&tree, &[0, 2]);
+        outside.claim.allowed_values = vec![[1; 32], [2; 32]];
+        let err = outside.verify().unwrap_err();
+        assert_eq!(err.to_string(), "leaf 2 holds a value that is not allowed");
+
+        // Unset leaves hold the empty leaf, which must be allowed as well.
+        let mut unset = input(&tree, &[0, 9]);
+        unset.claim.allowed_values = vec![[1; 32]];
+        assert!(unset.verify().is_err());
+        unset.claim.allowed_values.push([0; 32]);
+        unset.verify().unwrap();
+    }
+}
@@ -38,16 +38,18 @@
 #![allow(clippy::clone_on_copy)]
 
 use anyhow::{bail, Error, Result};
-use ark_std::Zero;
-use digest::{consts::U256, Digest};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use digest::Digest;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::index::{
+    convert_index_to_last_level, is_left_child, is_root, left_child, parent, right_child, sibling,
+};
 use std::{
     borrow::ToOwned,
     collections::{BTreeMap, BTreeSet},
     fmt::Debug,
-    io::Read,
     marker::PhantomData,
-    ops::{Add, AddAssign},
+    sync::Arc,
 };
 
 /// Error enum for Sparse Merkle Tree.
@@ -57,6 +59,34 @@ pub enum MerkleError {
     InvalidLeaf,
     /// Thrown when the merkle path is invalid.
     InvalidPathNodes,
+    /// Thrown when the reconstructed root differs from the expected root.
+    RootMismatch { computed: String, expected: String },
+    /// Thrown when a leaf index is listed more than once.
+    DuplicateLeaf { index: u64 },
+    /// Thrown when a leaf index does not fit in a tree of the given capacity.
+    IndexOutOfRange { index: u64, capacity: u64 },
+    /// Thrown when a leaf that must be empty holds a value.
+    NonEmptyLeaf { index: u64 },
+    /// Thrown when a stored interior node is not the hash of its children.
+    NodeMismatch { index: u64 },
+    /// Thrown when the leaves need a taller tree than the one requested.
+    TreeTooSmall { required: usize, available: usize },
+    /// Thrown when an empty hash is not derived from the empty leaf.
+    EmptyHashMismatch { level: usize },
+    /// Thrown when the empty hash chain doesn't have one entry per level.
+    InvalidEmptyHashes { len: usize, height: usize },
+    /// Thrown when a node index does not fit in a tree of the given height.
+    NodeOutOfRange { index: u64 },
+    /// Thrown when a leaf expected to be a left child is a right child.
+    NotLeftChild { index: u64 },
+    /// Thrown when a level is not below the root of a tree of the given height.
+    LevelOutOfRange { level: usize, height: usize },
+    /// Thrown when a leaf index is not past the one listed before it.
+    UnsortedLeaves { index: u64 },
+    /// Thrown when a fixed-capacity vector is full.
+    CapacityExceeded { capacity: usize },
+    /// Thrown when an empty region is not a subtree below the root.
+    InvalidRegion { start: u64, end: u64 },
 }
 
 impl core::fmt::Display for MerkleError {
@@ -64,6 +94,44 @@ impl core::fmt::Display for MerkleError {
         let msg = match self {
             MerkleError::InvalidLeaf => "Invalid leaf".to_owned(),
             MerkleError::InvalidPathNodes => "Path nodes are not consistent".to_owned(),
+            MerkleError::RootMismatch { computed, expected } => {
+                format!("Computed root {} but expected {}", computed, expected)
+            }
+            MerkleError::DuplicateLeaf { index } => format!("Duplicate leaf {}", index),
+            MerkleError::IndexOutOfRange { index, capacity } => {
+                format!("Leaf {} is out of range for {} leaves", index, capacity)
+            }
+            MerkleError::NonEmptyLeaf { index } => format!("Leaf {} is not empty", index),
+            MerkleError::NodeMismatch { index } => {
+                format!("Node {} is not the hash of its children", index)
+            }
+            MerkleError::TreeTooSmall {
+                required,
+                available,
+            } => format!(
+                "Leaves need a tree of height {} but the height is {}",
+                required, available
+            ),
+            MerkleError::InvalidEmptyHashes { len, height } => {
+                format!("{} empty hashes for a tree of height {}", len, height)
+            }
+            MerkleError::EmptyHashMismatch { level } => {
+                format!("Empty hash at level {} is not derived from the empty leaf", level)
+            }
+            MerkleError::NodeOutOfRange { index } => format!("Node {} is out of range", index),
+            MerkleError::NotLeftChild { index } => format!("Leaf {} is not a left child", index),
+            MerkleError::LevelOutOfRange { level, height } => {
+                format!("Level {} is out of range for a tree of height {}", level, height)
+            }
+            MerkleError::UnsortedLeaves { index } => {
+                format!("Leaf {} is listed after a higher leaf", index)
+            }
+            MerkleError::CapacityExceeded { capacity } => {
+                format!("Vector capacity of {} exceeded", capacity)
+            }
+            MerkleError::InvalidRegion { start, end } => {
+                format!("Leaves {}..{} are not a subtree below the root", start, end)
+            }
         };
         write!(f, "{}", msg)
     }
@@ -71,42 +139,273 @@ impl core::fmt::Display for MerkleError {
 
 impl std::error::Error for MerkleError {}
 
+/// Why `PartialTree::verify_collect` failed.
+#[derive(Debug)]
+pub enum VerifyError<F> {
+    /// The tree is malformed, e.g. its empty hash chain is broken, so there
+    /// are no nodes to compare.
+    Structure(Error),
+    /// Every `(index, expected, got)` node that differs from the one
+    /// recomputed from the leaves, bottom up. Never empty.
+    Mismatches(Vec<(u64, F, F)>),
+}
+
+impl<F> core::fmt::Display for VerifyError<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VerifyError::Structure(err) => write!(f, "{}", err),
+            VerifyError::Mismatches(mismatches) => {
+                write!(f, "{} nodes differ from the recomputed ones", mismatches.len())
+            }
+        }
+    }
+}
+
+impl<F: Debug> std::error::Error for VerifyError<F> {}
+
+/// A tree node. With the `serde` feature, nodes must also be serializable so
+/// that proofs and partial trees can be sent to the guest.
+#[cfg(feature = "serde")]
 pub trait FieldExt: Clone + Eq + Copy + ToOwned<Owned = Self> + Serialize + Default {}
+#[cfg(not(feature = "serde"))]
+pub trait FieldExt: Clone + Eq + Copy + ToOwned<Owned = Self> + Default {}
+/// A hash function over `W` nodes.
+///
+/// `hash` must be a pure function of `nodes`: the tree hashes one node per
+/// call, in no particular order, and relies on the same nodes always hashing
+/// to the same value. An implementation holding state behind `&self`, e.g. a
+/// running digest, must start every call from the same state.
 pub trait FieldHasher<F, const W: usize> {
     fn hash(&self, nodes: [F; W]) -> Result<F>;
 }
 
+/// A hasher that maps arbitrary bytes to a field element, used to commit to
+/// secrets stored as leaves.
+pub trait BytesHasher<F> {
+    fn hash_bytes(&self, bytes: &[u8]) -> Result<F>;
+}
+
 /// The Path struct.
 ///
 /// The path contains a sequence of sibling nodes that make up a merkle proof.
 /// Each pair is used to identify whether an incremental merkle root
 /// construction is valid at each intermediate step.
-#[derive(Clone, Serialize, Deserialize, Debug)]
+///
+/// Like trees, paths of a height `N` above 63 fail to compile where they
+/// index nodes:
+///
+/// ```compile_fail
+/// use sparse_tree::smt::Path;
+///
+/// let path = Path::<[u8; 32], 64> { path: heapless::Vec::new() };
+/// path.node_indices(0);
+/// ```
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Path<F: FieldExt, const N: usize> {
     /// The path represented as a sequence of sibling pairs.
     pub path: heapless::Vec<(F, F), N>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Proof<F: FieldExt, const N: usize> {
     pub path: Path<F, N>,
     pub root: F,
     pub leaf: F,
+    /// Whether the leaf was inserted, as opposed to never set, in a tree built
+    /// with `SparseMerkleTree::with_presence`. The path then holds
+    /// `present_leaf` of `leaf`, so the root binds it. Always false for other
+    /// trees.
+    pub present: bool,
+}
+
+impl<F: FieldExt + Debug, const N: usize> Proof<F, N> {
+    /// Checks that the path reconstructs `root` from `leaf`. A path leading to
+    /// another root is reported as `MerkleError::RootMismatch`.
+    pub fn verify<H: FieldHasher<F, 2>>(&self, h: &H) -> Result<bool> {
+        let computed = self.verify_root(h)?;
+        if computed != self.root {
+            return Err(MerkleError::RootMismatch {
+                computed: format!("{:?}", computed),
+                expected: format!("{:?}", self.root),
+            }
+            .into());
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the root reconstructed from `leaf` and the path, to be compared
+    /// against a trusted root.
+    pub fn verify_root<H: FieldHasher<F, 2>>(&self, h: &H) -> Result<F> {
+        self.calculate_root(h)
+    }
+
+    /// Returns the root the path leads to from the node of `leaf`, see
+    /// `Path::calculate_root`.
+    pub fn calculate_root<H: FieldHasher<F, 2>>(&self, h: &H) -> Result<F> {
+        if self.present {
+            self.path.calculate_root(&present_leaf(&self.leaf, h)?, h)
+        } else {
+            self.path.calculate_root(&self.leaf, h)
+        }
+    }
+
+    /// Like `verify`, for the proof of the leaf at map index `index` from a
+    /// tree built with `SparseMerkleTree::with_presence`.
+    ///
+    /// The path is followed through the position of `index`, see
+    /// `Path::calculate_root_at`: inferring the side would accept an unset
+    /// sibling as the leaf. And every leaf of such a tree that is not present
+    /// holds `empty_leaf`, so `present` can't be dropped from a proof of an
+    /// inserted leaf either.
+    pub fn verify_with_presence<H: FieldHasher<F, 2>>(
+        &self,
+        index: u64,
+        h: &H,
+        empty_leaf: &F,
+    ) -> Result<bool> {
+        if !self.present && self.leaf != *empty_leaf {
+            bail!("a leaf that is not present holds a value");
+        }
+        let node = if self.present { present_leaf(&self.leaf, h)? } else { self.leaf.clone() };
+        let computed = self.path.calculate_root_at(&node, index, h)?;
+        if computed != self.root {
+            return Err(MerkleError::RootMismatch {
+                computed: format!("{:?}", computed),
+                expected: format!("{:?}", self.root),
+            }
+            .into());
+        }
+
+        Ok(true)
+    }
+}
+
+/// Fixed-size encoding of a proof over 32-byte elements.
+///
+/// The layout is the `N` path pairs from the leaf level up, left then right,
+/// followed by the root and the leaf, `32 * (2 * N + 2)` bytes in total.
+///
+/// A path shorter than `N` is padded at the top: the pair of each missing
+/// level `l` is `(empty_hashes[l], empty_hashes[l])`. The padding can't be
+/// told apart from the path, so it decodes as part of a full path, and only
+/// proofs with a full path roundtrip unchanged. `present` is not encoded: it
+/// decodes as false, and a proof with `present` set is rejected.
+impl<const N: usize> Proof<BYTE32, N> {
+    pub const FIXED_SIZE: usize = 32 * (2 * N + 2);
+
+    /// Writes the proof into `out`, which must be `FIXED_SIZE` bytes long.
+    /// `empty_hashes` are the empty hashes of the tree, see
+    /// `SparseMerkleTree::empty_hashes`, from which a short path is padded.
+    pub fn to_fixed_bytes(&self, empty_hashes: &[BYTE32], out: &mut [u8]) -> Result<()> {
+        if out.len() != Self::FIXED_SIZE {
+            bail!("expected a buffer of {} bytes, got {}", Self::FIXED_SIZE, out.len());
+        }
+        let len = self.path.path.len();
+        if len < N && empty_hashes.len() < N {
+            return Err(MerkleError::InvalidEmptyHashes {
+                len: empty_hashes.len(),
+                height: N,
+            }
+            .into());
+        }
+        if self.present {
+            bail!("the fixed-size encoding doesn't carry presence");
+        }
+
+        let mut chunks = out.chunks_exact_mut(32);
+        let nodes = self
+            .path
+            .path
+            .iter()
+            .flat_map(|(left, right)| [left, right])
+            .chain(empty_hashes.iter().take(N).skip(len).flat_map(|empty| [empty, empty]))
+            .chain([&self.root, &self.leaf]);
+        for (chunk, node) in (&mut chunks).zip(nodes) {
+            chunk.copy_from_slice(node);
+        }
+
+        Ok(())
+    }
+
+    pub fn from_fixed_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != Self::FIXED_SIZE {
+            bail!("expected {} bytes, got {}", Self::FIXED_SIZE, bytes.len());
+        }
+
+        let mut nodes = bytes.chunks_exact(32).map(|chunk| {
+            let mut node = [0; 32];
+            node.copy_from_slice(chunk);
+            node
+        });
+        let mut path = heapless::Vec::new();
+        for _ in 0..N {
+            let left = nodes.next().unwrap();
+            let right = nodes.next().unwrap();
+            try_push(&mut path, (left, right))?;
+        }
+
+        Ok(Proof {
+            path: Path { path },
+            root: nodes.next().unwrap(),
+            leaf: nodes.next().unwrap(),
+            present: false,
+        })
+    }
+}
+
+/// A proof that two adjacent leaves share a parent.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SiblingProof<F: FieldExt, const N: usize> {
+    /// The map index of the left leaf, the right leaf is at `index + 1`.
+    pub index: u64,
+    pub left: F,
+    pub right: F,
+    pub parent: F,
+    /// The path from the parent to the root.
+    pub path: Path<F, N>,
+    pub root: F,
 }
 
-impl<F: FieldExt + Serialize + DeserializeOwned, const N: usize> Proof<F, N>
-where
-    [(F, F); N]: DeserializeOwned + Serialize,
-{
+impl<F: FieldExt + Debug, const N: usize> SiblingProof<F, N> {
+    /// Checks that `parent` is the hash of the two leaves, and that the path
+    /// leads from it to `root` through the position of `index`.
     pub fn verify<H: FieldHasher<F, 2>>(&self, h: &H) -> Result<bool> {
-        self.path.check_membership(&self.root, &self.leaf, h)
+        if self.index & 1 == 1 {
+            return Err(MerkleError::NotLeftChild { index: self.index }.into());
+        }
+        if h.hash([self.left.clone(), self.right.clone()])? != self.parent {
+            let index = parent(convert_index_to_last_level(self.index, N)).unwrap_or(0);
+            return Err(MerkleError::NodeMismatch { index }.into());
+        }
+
+        if self.path.path.len() + 1 != N {
+            return Err(MerkleError::InvalidPathNodes.into());
+        }
+        let mut prev = self.parent.clone();
+        for (level, (left, right)) in self.path.path.iter().enumerate() {
+            let node = if (self.index >> (level + 1)) & 1 == 1 { right } else { left };
+            if *node != prev {
+                return Err(MerkleError::InvalidPathNodes.into());
+            }
+            prev = h.hash([left.clone(), right.clone()])?;
+        }
+        if prev != self.root {
+            return Err(MerkleError::RootMismatch {
+                computed: format!("{:?}", prev),
+                expected: format!("{:?}", self.root),
+            }
+            .into());
+        }
+
+        Ok(true)
     }
 }
 
-impl<F: FieldExt + Serialize + DeserializeOwned, const N: usize> Path<F, N>
-where
-    [(F, F); N]: DeserializeOwned + Serialize,
-{
+impl<F: FieldExt, const N: usize> Path<F, N> {
     /// Takes in an expected `root_hash` and leaf-level data (i.e. hashes of
     /// secrets) for a leaf and checks that the leaf belongs to a tree having
     /// the expected hash.
@@ -123,6 +422,9 @@ where
     /// Assumes leaf contains leaf-level data, i.e. hashes of secrets
     /// stored on leaf-level.
     pub fn calculate_root<H: FieldHasher<F, 2>>(&self, leaf: &F, hasher: &H) -> Result<F, Error> {
+        if self.path.is_empty() {
+            return Err(MerkleError::InvalidPathNodes.into());
+        }
         if *leaf != self.path[0].0 && *leaf != self.path[0].1 {
             return Err(MerkleError::InvalidLeaf.into());
         }
@@ -138,6 +440,174 @@ where
 
         Ok(prev)
     }
+
+    /// Like `calculate_root`, but takes the map index of the leaf rather than
+    /// inferring the side of the running node at each level by comparing it
+    /// against both nodes of the pair, which can't tell the sides apart when
+    /// they are equal. The path must have one pair per level.
+    pub fn calculate_root_at<H: FieldHasher<F, 2>>(
+        &self,
+        leaf: &F,
+        index: u64,
+        hasher: &H,
+    ) -> Result<F, Error> {
+        let () = Height::<N>::CHECK;
+        check_range::<N>(index)?;
+        if self.path.len() != N {
+            return Err(MerkleError::InvalidPathNodes.into());
+        }
+
+        let mut prev = leaf.clone();
+        for (level, (left, right)) in self.path.iter().enumerate() {
+            let node = if (index >> level) & 1 == 1 { right } else { left };
+            if *node != prev {
+                return Err(if level == 0 {
+                    MerkleError::InvalidLeaf
+                } else {
+                    MerkleError::InvalidPathNodes
+                }
+                .into());
+            }
+            prev = hasher.hash([left.clone(), right.clone()])?;
+        }
+
+        Ok(prev)
+    }
+
+    /// Same result as `check_membership`, but only the running node is kept.
+    /// With `Sha256` over `[u8; 32]`, nothing is allocated on the heap unless
+    /// it fails.
+    pub fn verify_in_place<H: FieldHasher<F, 2>>(
+        &self,
+        root: &F,
+        leaf: &F,
+        hasher: &H,
+    ) -> Result<bool, Error> {
+        let Some((left, right)) = self.path.first() else {
+            return Err(MerkleError::InvalidPathNodes.into());
+        };
+        if leaf != left && leaf != right {
+            return Err(MerkleError::InvalidLeaf.into());
+        }
+
+        let mut prev = *leaf;
+        for (left, right) in &self.path {
+            if prev != *left && prev != *right {
+                return Err(MerkleError::InvalidPathNodes.into());
+            }
+            prev = hasher.hash([*left, *right])?;
+        }
+
+        Ok(prev == *root)
+    }
+
+    /// Returns the absolute tree indices of the `(left, right)` pair at each
+    /// level of the path leading from the leaf at `leaf_index` to the root.
+    /// Fails with `MerkleError::IndexOutOfRange` unless `leaf_index` is below
+    /// `2^N`.
+    pub fn node_indices(&self, leaf_index: u64) -> Result<heapless::Vec<(u64, u64), N>, Error> {
+        let () = Height::<N>::CHECK;
+        check_range::<N>(leaf_index)?;
+        let mut indices = heapless::Vec::new();
+
+        let mut current_node = convert_index_to_last_level(leaf_index, N);
+        for _ in 0..self.path.len() {
+            let sibling_node = match sibling(current_node) {
+                Some(i) => i,
+                None => break,
+            };
+            let pair = if is_left_child(current_node) {
+                (current_node, sibling_node)
+            } else {
+                (sibling_node, current_node)
+            };
+            try_push(&mut indices, pair)?;
+            current_node = match parent(current_node) {
+                Some(i) => i,
+                None => break,
+            };
+        }
+
+        Ok(indices)
+    }
+
+    /// Compresses the path of the leaf at `index` by leaving out the siblings
+    /// that are empty hashes, which are recorded in a bitmap instead.
+    pub fn compress(&self, index: u64, empty_hashes: &[F]) -> Result<CompressedPath<F, N>, Error> {
+        if self.path.len() > 64 || empty_hashes.len() < self.path.len() {
+            return Err(MerkleError::InvalidPathNodes.into());
+        }
+
+        let mut compressed = CompressedPath {
+            index,
+            bitmap: 0,
+            siblings: heapless::Vec::new(),
+        };
+        for (level, (left, right)) in self.path.iter().enumerate() {
+            let sibling = if (index >> level) & 1 == 1 { left } else { right };
+            if *sibling != empty_hashes[level] {
+                compressed.bitmap |= 1 << level;
+                try_push(&mut compressed.siblings, sibling.clone())?;
+            }
+        }
+
+        Ok(compressed)
+    }
+}
+
+/// A `Path` that only stores the non-empty siblings.
+///
+/// The nodes on the path itself are recomputed from the leaf, and each empty
+/// sibling is taken from the tree's empty hash chain.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CompressedPath<F: FieldExt, const N: usize> {
+    /// The map index of the leaf, which decides the side of the path nodes.
+    pub index: u64,
+    /// Bit `k` is set iff the sibling at level `k` is not empty.
+    pub bitmap: u64,
+    /// The non-empty siblings, from the leaf level up.
+    pub siblings: heapless::Vec<F, N>,
+}
+
+impl<F: FieldExt, const N: usize> CompressedPath<F, N> {
+    /// Rebuilds the full path of `leaf`.
+    pub fn decompress<H: FieldHasher<F, 2>>(
+        &self,
+        leaf: &F,
+        empty_hashes: &[F],
+        hasher: &H,
+    ) -> Result<Path<F, N>, Error> {
+        if N > 64 || empty_hashes.len() < N {
+            return Err(MerkleError::InvalidPathNodes.into());
+        }
+
+        let mut path = heapless::Vec::new();
+        let mut siblings = self.siblings.iter();
+        let mut prev = leaf.clone();
+        for level in 0..N {
+            let sibling = if (self.bitmap >> level) & 1 == 1 {
+                match siblings.next() {
+                    Some(s) => s.clone(),
+                    None => return Err(MerkleError::InvalidPathNodes.into()),
+                }
+            } else {
+                empty_hashes[level].clone()
+            };
+            let pair = if (self.index >> level) & 1 == 1 {
+                (sibling, prev)
+            } else {
+                (prev, sibling)
+            };
+            prev = hasher.hash([pair.0.clone(), pair.1.clone()])?;
+            try_push(&mut path, pair)?;
+        }
+        if siblings.next().is_some() {
+            return Err(MerkleError::InvalidPathNodes.into());
+        }
+
+        Ok(Path { path })
+    }
 }
 
 /// The Sparse Merkle Tree struct.
@@ -145,42 +615,175 @@ where
 /// The Sparse Merkle Tree stores a set of leaves represented in a map and
 /// a set of empty hashes that it uses to represent the sparse areas of the
 /// tree.
+///
+/// The height `N` is at most 63, so that every node index fits in a `u64`.
+/// Taller trees fail to compile:
+///
+/// ```compile_fail
+/// use sha2::{Digest, Sha256};
+/// use sparse_tree::smt::SparseMerkleTree;
+///
+/// let tree = SparseMerkleTree::<[u8; 32], Sha256, 64>::new_sequential(&[], &Sha256::new(), [0; 32]);
+/// ```
 pub struct SparseMerkleTree<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> {
     /// A map from leaf indices to leaf data stored as field elements.
     pub tree: BTreeMap<u64, F>,
-    /// An array of default hashes hashed with themselves `N` times.
-    empty_hashes: heapless::Vec<F, N>,
+    /// An array of default hashes hashed with themselves `N` times, shared
+    /// by the trees of a `Forest`.
+    empty_hashes: Arc<heapless::Vec<F, N>>,
+    /// Parents of the leaves changed since the last flush.
+    dirty: BTreeSet<u64>,
+    /// Map indices of the leaves set by `insert`, whatever their value.
+    present: BTreeSet<u64>,
+    /// The region with its own empty leaf, if any.
+    region: Option<Arc<RegionHashes<F, N>>>,
+    /// With `with_presence`, the node of each inserted leaf as of the last
+    /// flush, see `present_leaf`, by tree index.
+    presence: Option<BTreeMap<u64, F>>,
+    /// The root as of the last flush.
+    root: F,
     /// The phantom hasher type used to build the merkle tree.
     marker: PhantomData<H>,
 }
 
+// Derived `Clone` would require `H: Clone`, although only the marker refers to
+// the hasher.
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> Clone for SparseMerkleTree<F, H, N> {
+    fn clone(&self) -> Self {
+        SparseMerkleTree {
+            tree: self.tree.clone(),
+            empty_hashes: self.empty_hashes.clone(),
+            dirty: self.dirty.clone(),
+            present: self.present.clone(),
+            region: self.region.clone(),
+            presence: self.presence.clone(),
+            root: self.root.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
 impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> SparseMerkleTree<F, H, N> {
     /// Takes a batch of field elements, inserts
     /// these hashes into the tree, and updates the merkle root.
+    ///
+    /// Leaves inserted with `insert` since the last flush are flushed as well.
     pub fn insert_batch(&mut self, leaves: &BTreeMap<u32, F>, hasher: &H) -> Result<(), Error> {
-        let last_level_index: u64 = (1u64 << N) - 1;
-        let mut level_idxs: BTreeSet<u64> = BTreeSet::new();
+        self.try_insert_batch(leaves, hasher)
+    }
+
+    /// Like `insert_batch`, but checks every index against the capacity
+    /// before inserting anything, so a batch with an out of range index is
+    /// rejected as a whole with `MerkleError::IndexOutOfRange`.
+    pub fn try_insert_batch(&mut self, leaves: &BTreeMap<u32, F>, hasher: &H) -> Result<(), Error> {
+        for i in leaves.keys() {
+            check_range::<N>(*i as u64)?;
+        }
         for (i, leaf) in leaves {
-            let true_index = last_level_index + (*i as u64);
-            self.tree.insert(true_index, leaf.clone());
-            let idx = parent(true_index);
-            if let Some(idx) = idx {
-                level_idxs.insert(idx);
-            } else {
-                bail!("parent not found");
+            self.insert(*i as u64, leaf.clone())?;
+        }
+
+        self.flush(hasher)
+    }
+
+    /// Inserts the leaves of each chunk in turn, and flushes after each
+    /// chunk, so the tree is consistent and `root` is current between chunks.
+    ///
+    /// Each chunk is checked like a `try_insert_batch`. Chunks before a failing
+    /// chunk stay inserted.
+    ///
+    /// Only the current chunk and its dirty nodes are held in memory next to
+    /// the tree. One `insert_batch` of all the leaves would hold every leaf
+    /// and every dirty interior node at once. The tree still stores all of
+    /// its non-empty nodes, so chunking bounds the transient memory, not the
+    /// size of the tree. It costs more hashing, since nodes shared by leaves
+    /// of different chunks are hashed once per chunk.
+    pub fn insert_chunked(
+        &mut self,
+        chunks: impl Iterator<Item = BTreeMap<u64, F>>,
+        hasher: &H,
+    ) -> Result<(), Error> {
+        for chunk in chunks {
+            for i in chunk.keys() {
+                check_range::<N>(*i)?;
+            }
+            for (i, leaf) in chunk {
+                self.insert(i, leaf)?;
             }
+            self.flush(hasher)?;
         }
 
-        for level in 0..N {
+        Ok(())
+    }
+
+    /// Inserts a leaf without updating the interior nodes.
+    ///
+    /// The root and the paths are stale until the next `flush`, which
+    /// recomputes each interior node affected by the pending inserts once.
+    pub fn insert(&mut self, index: u64, leaf: F) -> Result<(), Error> {
+        check_range::<N>(index)?;
+        let true_index = convert_index_to_last_level(index, N);
+        self.tree.insert(true_index, leaf);
+        self.present.insert(index);
+        if let Some(idx) = parent(true_index) {
+            self.dirty.insert(idx);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the leaf at `index`, and updates the merkle root.
+    pub fn delete_leaf(&mut self, index: u64, hasher: &H) -> Result<(), Error> {
+        check_range::<N>(index)?;
+        let true_index = convert_index_to_last_level(index, N);
+        self.tree.remove(&true_index);
+        self.present.remove(&index);
+        if let Some(idx) = parent(true_index) {
+            self.dirty.insert(idx);
+        }
+
+        self.flush(hasher)
+    }
+
+    /// Recomputes the paths from the given leaves to the root, after the
+    /// leaves were changed directly through the `tree` map.
+    ///
+    /// Leaves are addressed by their index in the last level, as in `insert`.
+    pub fn recompute_from(&mut self, dirty_leaf_indices: &[u64], hasher: &H) -> Result<(), Error> {
+        for &index in dirty_leaf_indices {
+            check_range::<N>(index)?;
+        }
+        for &index in dirty_leaf_indices {
+            if let Some(idx) = parent(convert_index_to_last_level(index, N)) {
+                self.dirty.insert(idx);
+            }
+        }
+
+        self.flush(hasher)
+    }
+
+    /// Returns true iff there are inserts that have not been flushed yet.
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Recomputes the interior nodes above the leaves changed since the last
+    /// flush, and caches the new root.
+    pub fn flush(&mut self, hasher: &H) -> Result<(), Error> {
+        let mut level_idxs = std::mem::take(&mut self.dirty);
+        if self.presence.is_some() {
+            for i in &level_idxs {
+                for leaf in [left_child(*i), right_child(*i)] {
+                    self.hash_present_leaf(leaf, hasher)?;
+                }
+            }
+        }
+        for _ in 0..N {
             let mut new_idxs: BTreeSet<u64> = BTreeSet::new();
-            let empty_hash = self.empty_hashes[level].clone();
             for i in level_idxs {
-                let left_index = left_child(i);
-                let right_index = right_child(i);
-                let left = self.tree.get(&left_index).unwrap_or(&empty_hash);
-                let right = self.tree.get(&right_index).unwrap_or(&empty_hash);
-                self.tree
-                    .insert(i, hasher.hash([left.clone(), right.clone()])?);
+                let left = self.node(left_child(i));
+                let right = self.node(right_child(i));
+                self.tree.insert(i, hasher.hash([left, right])?);
 
                 let parent = match parent(i) {
                     Some(i) => i,
@@ -190,6 +793,9 @@ impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> SparseMerkleTree<F, H, N
             }
             level_idxs = new_idxs;
         }
+        if let Some(root) = self.tree.get(&0) {
+            self.root = root.clone();
+        }
 
         Ok(())
     }
@@ -197,19 +803,37 @@ impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> SparseMerkleTree<F, H, N
     /// Creates a new Sparse Merkle Tree from a map of indices to field
     /// elements.
     pub fn new(leaves: &BTreeMap<u32, F>, hasher: &H, empty_leaf: F) -> Result<Self, Error> {
-        // Ensure the tree can hold this many leaves
-        let last_level_size = leaves.len().next_power_of_two();
-        let tree_size = 2 * last_level_size - 1;
-        let tree_height = tree_height(tree_size as u64);
-        assert!(tree_height <= N as u32);
+        let () = Height::<N>::CHECK;
+        // Ensure the tree can hold the highest index, which may be far past
+        // the number of leaves. A tree of height `N` has `2^N` leaves, and
+        // needs at least one level above the leaves.
+        let size = leaves.keys().next_back().map_or(0, |i| *i as u64 + 1);
+        let last_level_size = size.next_power_of_two();
+        let tree_height = (log2(last_level_size) as usize).max(1);
+        if tree_height > N {
+            return Err(MerkleError::TreeTooSmall {
+                required: tree_height,
+                available: N,
+            }
+            .into());
+        }
 
         // Initialize the merkle tree
         let tree: BTreeMap<u64, F> = BTreeMap::new();
         let empty_hashes = gen_empty_hashes(hasher, empty_leaf)?;
 
+        // The root of the empty tree, one level above the last empty hash, as
+        // reconstructed by any proof against the empty tree.
+        let top = empty_hashes[N - 1].clone();
+        let root = hasher.hash([top.clone(), top])?;
         let mut smt = SparseMerkleTree::<F, H, N> {
             tree,
-            empty_hashes,
+            empty_hashes: Arc::new(empty_hashes),
+            dirty: BTreeSet::new(),
+            present: BTreeSet::new(),
+            region: None,
+            presence: None,
+            root,
             marker: PhantomData,
         };
         smt.insert_batch(leaves, hasher)?;
@@ -217,307 +841,3481 @@ impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> SparseMerkleTree<F, H, N
         Ok(smt)
     }
 
-    /// Creates a new Sparse Merkle Tree from an array of field elements.
-    pub fn new_sequential(leaves: &[F], hasher: &H, empty_leaf: F) -> Result<Self, Error> {
-        let pairs: BTreeMap<u32, F> = leaves
-            .iter()
-            .enumerate()
-            .map(|(i, l)| (i as u32, l.clone()))
-            .collect();
-        let smt = Self::new(&pairs, hasher, empty_leaf)?;
+    /// Like `new`, but the unset leaves in `region` hold the region's empty
+    /// leaf rather than `empty_leaf`.
+    ///
+    /// Roots, paths and partial trees all take the region into account.
+    /// `subtree` and `update_batch` don't support a tree with a region.
+    pub fn with_empty_region(
+        leaves: &BTreeMap<u32, F>,
+        hasher: &H,
+        empty_leaf: F,
+        region: EmptyRegion<F>,
+    ) -> Result<Self, Error> {
+        let mut smt = Self::new(&BTreeMap::new(), hasher, empty_leaf)?;
+        let region = RegionHashes::new(region, &smt.empty_hashes, hasher)?;
+        smt.root = region.empty_node(0, &smt.empty_hashes);
+        smt.region = Some(Arc::new(region));
+        smt.insert_batch(leaves, hasher)?;
 
         Ok(smt)
     }
 
-    /// Returns the Merkle tree root.
-    pub fn root(&self) -> F {
-        self.tree
-            .get(&0)
-            .cloned()
-            .unwrap_or(self.empty_hashes.last().unwrap().clone())
-    }
-
-    /// Give the path leading from the leaf at `index` up to the root.  This is
-    /// a "proof" in the sense of "valid path in a Merkle tree", not a ZK
-    /// argument.
-    pub fn generate_membership_path(&self, index: u64) -> Path<F, N> {
-        let mut path = heapless::Vec::new();
-
-        let tree_index = convert_index_to_last_level(index, N);
-
-        // Iterate from the leaf up to the root, storing all intermediate hash values.
-        let mut current_node = tree_index;
-        let mut level = 0;
-        while !is_root(current_node) {
-            let sibling_node = sibling(current_node).unwrap();
+    /// Like `new`, but the root also commits to which leaves were inserted:
+    /// the node of an inserted leaf is `present_leaf` of its value, and an
+    /// unset leaf is the empty leaf. So a leaf set to the empty leaf and one
+    /// never set have different roots, and `Proof::present` and
+    /// `PartialTree::present` are bound by the root.
+    ///
+    /// Paths hold leaf nodes, proofs still hold leaf values. `subtree`,
+    /// `update_batch` and `prove_siblings` don't support such a tree.
+    pub fn with_presence(leaves: &BTreeMap<u32, F>, hasher: &H, empty_leaf: F) -> Result<Self> {
+        let mut smt = Self::new(&BTreeMap::new(), hasher, empty_leaf)?;
+        smt.presence = Some(BTreeMap::new());
+        smt.insert_batch(leaves, hasher)?;
 
-            let empty_hash = &self.empty_hashes[level];
+        Ok(smt)
+    }
 
-            let current = self.tree.get(&current_node).cloned().unwrap_or(*empty_hash);
-            let sibling = self.tree.get(&sibling_node).cloned().unwrap_or(*empty_hash);
+    /// Returns true iff the tree was built with `with_presence`.
+    pub fn binds_presence(&self) -> bool {
+        self.presence.is_some()
+    }
 
-            if is_left_child(current_node) {
-                path[level] = (current, sibling);
-            } else {
-                path[level] = (sibling, current);
-            }
-            current_node = parent(current_node).unwrap();
-            level += 1;
+    /// Updates the node of the leaf at tree index `leaf` in `presence`.
+    fn hash_present_leaf(&mut self, leaf: u64, hasher: &H) -> Result<()> {
+        let index = leaf - convert_index_to_last_level(0, N);
+        let node = match self.tree.get(&leaf) {
+            Some(value) if self.present.contains(&index) => Some(present_leaf(value, hasher)?),
+            _ => None,
+        };
+        if let Some(presence) = &mut self.presence {
+            match node {
+                Some(node) => presence.insert(leaf, node),
+                None => presence.remove(&leaf),
+            };
         }
 
-        Path { path }
+        Ok(())
     }
 
-    pub fn generate_membership_proof(&self, index: u64) -> Proof<F, N> {
-        let empty_hash = &self.empty_hashes[0];
-        let tree_index = convert_index_to_last_level(index, N);
+    /// Returns the region with its own empty leaf, if the tree has one.
+    pub fn empty_region(&self) -> Option<&EmptyRegion<F>> {
+        self.region.as_ref().map(|r| &r.region)
+    }
 
-        Proof {
-            path: self.generate_membership_path(index),
-            root: self.root(),
-            leaf: self.tree.get(&tree_index).unwrap_or(empty_hash).to_owned(),
+    /// Returns the node at tree index `index`, the empty node of its level and
+    /// region if it is not stored.
+    fn node(&self, index: u64) -> F {
+        match self.stored_node(index) {
+            Some(node) => node.clone(),
+            None => self.empty_node(index),
         }
     }
 
-    /// Leaves as in leaf in index in the leaf vector
-    pub fn batch_prove(&self, leaves: &[u64]) -> PartialTree<F, N> {
-        let mut partial = PartialTree {
-            empty_hashes: self.empty_hashes.to_owned(),
-            root: self.root(),
-            ..Default::default()
-        };
-
-        for leaf in leaves {
-            partial.leaves.push(*leaf);
-
-            let tree_index = convert_index_to_last_level(*leaf, N);
-
-            // Iterate from the leaf up to the root, storing all intermediate hash values.
-            let mut current_node = tree_index;
-            let mut level = 0;
-
-            while !is_root(current_node) {
-                let sibling_node = sibling(current_node).unwrap();
+    /// Returns the node at tree index `index` if it is stored. For a leaf of a
+    /// tree built with `with_presence`, that is its node rather than its value.
+    fn stored_node(&self, index: u64) -> Option<&F> {
+        match self.presence.as_ref().and_then(|p| p.get(&index)) {
+            Some(node) => Some(node),
+            None => self.tree.get(&index),
+        }
+    }
 
-                let empty_hash = &self.empty_hashes[level];
+    /// Returns the value of the leaf at tree index `index`.
+    fn leaf(&self, index: u64) -> F {
+        match self.tree.get(&index) {
+            Some(leaf) => leaf.clone(),
+            None => self.empty_node(index),
+        }
+    }
 
-                let current = self.tree.get(&current_node).cloned().unwrap_or(*empty_hash);
-                let sibling = self.tree.get(&sibling_node).cloned().unwrap_or(*empty_hash);
+    /// Returns the value of the node at tree index `index` when no leaf below
+    /// it is set.
+    fn empty_node(&self, index: u64) -> F {
+        empty_node(index, &self.empty_hashes, self.region.as_deref())
+    }
 
-                if current != *empty_hash {
-                    partial.tree.insert(current_node, current);
+    /// Creates a new Sparse Merkle Tree from `(map index, leaf)` pairs in
+    /// strictly increasing index order.
+    ///
+    /// Builds the same tree as `new`, one level at a time, without going
+    /// through a map or the dirty set. A repeated index fails with
+    /// `MerkleError::DuplicateLeaf`, a decreasing one with
+    /// `MerkleError::UnsortedLeaves`.
+    pub fn from_sorted_pairs(pairs: &[(u64, F)], hasher: &H, empty_leaf: F) -> Result<Self, Error> {
+        let mut prev: Option<u64> = None;
+        for (index, _) in pairs {
+            check_range::<N>(*index)?;
+            match prev {
+                Some(p) if p == *index => {
+                    return Err(MerkleError::DuplicateLeaf { index: *index }.into())
                 }
-                if sibling != *empty_hash {
-                    partial.tree.insert(sibling_node, sibling);
+                Some(p) if p > *index => {
+                    return Err(MerkleError::UnsortedLeaves { index: *index }.into())
                 }
-
-                current_node = parent(current_node).unwrap();
-                level += 1;
+                _ => {}
             }
+            prev = Some(*index);
         }
 
-        partial
-    }
-}
+        let empty_hashes = gen_empty_hashes(hasher, empty_leaf)?;
+        let top = empty_hashes[N - 1].clone();
+        let mut root = hasher.hash([top.clone(), top])?;
 
-// Partial tree
-// Turn Vec<Path> Into a partial tree. Verify tree.
+        // Each level is sorted by tree index, so siblings are adjacent and
+        // the parents come out sorted as well.
+        let mut tree = BTreeMap::new();
+        let mut nodes: Vec<(u64, F)> = pairs
+            .iter()
+            .map(|(i, leaf)| (convert_index_to_last_level(*i, N), leaf.clone()))
+            .collect();
+        for empty_hash in empty_hashes.iter() {
+            if nodes.is_empty() {
+                break;
+            }
+            tree.extend(nodes.iter().cloned());
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct PartialTree<F: FieldExt, const N: usize> {
-    pub tree: BTreeMap<u64, F>,
-    empty_hashes: heapless::Vec<F, N>,
-    /// as in map index. not tree index
-    pub leaves: Vec<u64>,
-    pub root: F,
-}
+            let mut parents = Vec::with_capacity(nodes.len() / 2 + 1);
+            let mut level = nodes.into_iter().peekable();
+            while let Some((index, node)) = level.next() {
+                let (left, right) = if is_left_child(index) {
+                    match level.next_if(|(next, _)| *next == index + 1) {
+                        Some((_, right)) => (node, right),
+                        None => (node, empty_hash.clone()),
+                    }
+                } else {
+                    (empty_hash.clone(), node)
+                };
+                parents.push((parent(index).unwrap(), hasher.hash([left, right])?));
+            }
+            nodes = parents;
+        }
+        if let Some((_, top)) = nodes.first() {
+            root = top.clone();
+            tree.extend(nodes);
+        }
 
-impl<F: FieldExt + Debug, const N: usize> PartialTree<F, N> {
-    pub fn verify<H: FieldHasher<F, 2>>(&self, hasher: &H) -> anyhow::Result<()> where {
-        // #[cfg(not(feature = "notzk"))]
-        // {
-        //     use risc0_zkvm::guest::env;
-        //     env::commit(&self.root);
-        //     env::log("commited partial tree");
-        // }
+        Ok(SparseMerkleTree {
+            tree,
+            empty_hashes: Arc::new(empty_hashes),
+            dirty: BTreeSet::new(),
+            present: pairs.iter().map(|(i, _)| *i).collect(),
+            region: None,
+            presence: None,
+            root,
+            marker: PhantomData,
+        })
+    }
 
-        #[cfg(feature = "notzk")]
-        {
-            println!(
-                "Tree proof, total elements {}, leaves {}",
-                self.tree.len(),
-                self.leaves.len()
-            )
-        }
+    /// Creates a new Sparse Merkle Tree from an array of field elements.
+    pub fn new_sequential(leaves: &[F], hasher: &H, empty_leaf: F) -> Result<Self, Error> {
+        let pairs: BTreeMap<u32, F> = leaves
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (i as u32, l.clone()))
+            .collect();
+        let smt = Self::new(&pairs, hasher, empty_leaf)?;
+
+        Ok(smt)
+    }
+
+    /// Returns the number of leaves the tree can hold.
+    pub fn capacity(&self) -> u64 {
+        1u64 << N
+    }
+
+    /// Returns the number of leaves holding a value other than the empty leaf.
+    pub fn len(&self) -> usize {
+        let last_level_index: u64 = (1u64 << N) - 1;
+        self.tree
+            .range(last_level_index..)
+            .filter(|(i, leaf)| **leaf != self.empty_node(**i))
+            .count()
+    }
+
+    /// Returns the lowest index of a stored leaf holding `value`.
+    ///
+    /// This scans every stored leaf, so it is O(leaves) and meant for host
+    /// side tooling, not for the guest. Leaves that were never set are not
+    /// found, even when looking for the empty leaf.
+    pub fn find(&self, value: &F) -> Option<u64> {
         let last_level_index: u64 = (1u64 << N) - 1;
-        let mut level_idxs: BTreeSet<u64> = BTreeSet::new();
-        for i in &self.leaves {
-            let true_index = last_level_index + *i;
-            let idx = parent(true_index);
-            if let Some(idx) = idx {
-                level_idxs.insert(idx);
+        self.tree
+            .range(last_level_index..)
+            .find(|(_, leaf)| *leaf == value)
+            .map(|(i, _)| i - last_level_index)
+    }
+
+    /// Returns the membership proof of the leaf found by `find`, with the
+    /// same O(leaves) cost.
+    pub fn prove_value(&self, value: &F) -> Result<Option<Proof<F, N>>, Error> {
+        self.find(value).map(|i| self.generate_membership_proof(i)).transpose()
+    }
+
+    /// Returns true iff no leaf holds a value other than the empty leaf.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the empty hash of each level, starting with the empty leaf.
+    pub fn empty_hashes(&self) -> &[F] {
+        &self.empty_hashes
+    }
+
+    /// Returns true iff the leaf at `index` was set by an insert and not
+    /// deleted since, even if it was set to the empty leaf. Unless the tree
+    /// was built with `with_presence`, the root doesn't tell such a leaf apart
+    /// from one that was never set, and proofs don't carry it.
+    pub fn is_present(&self, index: u64) -> bool {
+        self.present.contains(&index)
+    }
+
+    /// Returns the Merkle tree root as of the last flush.
+    pub fn root(&self) -> F {
+        self.root
+    }
+
+    /// Returns the map indices of the leaves that hold different values in
+    /// `self` and `other`, in increasing order.
+    ///
+    /// Only the subtrees whose roots differ are visited, so this takes
+    /// O(differences * N) lookups. Both trees must be flushed. Subtrees that
+    /// neither tree stores are skipped, so with different empty leaves the
+    /// leaves unset in both trees are not reported.
+    pub fn diff(&self, other: &Self) -> Vec<u64> {
+        let mut differing = vec![];
+        if self.root() == other.root() {
+            return differing;
+        }
+
+        let first_leaf = convert_index_to_last_level(0, N);
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            if i >= first_leaf {
+                differing.push(i - first_leaf);
+                continue;
+            }
+            // The right child is pushed first, so leaves come out in order.
+            for child in [right_child(i), left_child(i)] {
+                let (ours, theirs) = (self.stored_node(child), other.stored_node(child));
+                if ours.is_none() && theirs.is_none() {
+                    continue;
+                }
+                let ours = ours.cloned().unwrap_or_else(|| self.empty_node(child));
+                let theirs = theirs.cloned().unwrap_or_else(|| other.empty_node(child));
+                if ours != theirs {
+                    stack.push(child);
+                }
+            }
+        }
+
+        differing
+    }
+
+    /// Returns the subtree rooted at the node at tree index `internal_index`,
+    /// as a tree of height `M`, which must be the level of that node.
+    ///
+    /// The subtree's leaves are the original leaves below the node, in order,
+    /// and its root is the node. Pending inserts below the node are carried
+    /// over, the subtree's root is stale until it is flushed.
+    pub fn subtree<const M: usize>(
+        &self,
+        internal_index: u64,
+    ) -> Result<SparseMerkleTree<F, H, M>, Error> {
+        if self.region.is_some() {
+            bail!("subtrees of a tree with an empty region are not supported");
+        }
+        if self.binds_presence() {
+            bail!("subtrees of a tree that binds presence are not supported");
+        }
+        if internal_index >= (1u64 << N) - 1 {
+            return Err(MerkleError::NodeOutOfRange {
+                index: internal_index,
+            }
+            .into());
+        }
+        let level = node_level(internal_index, N);
+        if level != M {
+            return Err(MerkleError::LevelOutOfRange { level, height: M }.into());
+        }
+
+        let mut empty_hashes = heapless::Vec::new();
+        for hash in &self.empty_hashes[..M] {
+            try_push(&mut empty_hashes, hash.clone())?;
+        }
+        // The descendants `d` levels below the node are a contiguous range of
+        // tree indices, which maps to the `d`-th level of the subtree.
+        let mut tree = BTreeMap::new();
+        let mut dirty = BTreeSet::new();
+        for depth in 0..=M {
+            let first = ((internal_index + 1) << depth) - 1;
+            let range = first..first + (1u64 << depth);
+            let new_first = (1u64 << depth) - 1;
+            for (i, node) in self.tree.range(range.clone()) {
+                tree.insert(new_first + (i - first), node.clone());
+            }
+            for i in self.dirty.range(range) {
+                dirty.insert(new_first + (i - first));
+            }
+        }
+        let first_leaf = ((internal_index + 1) << M) - 1 - ((1u64 << N) - 1);
+        let present = self
+            .present
+            .range(first_leaf..first_leaf + (1u64 << M))
+            .map(|i| i - first_leaf)
+            .collect();
+        let root = if is_root(internal_index) {
+            self.root()
+        } else {
+            *self.tree.get(&internal_index).unwrap_or(&self.empty_hashes[level])
+        };
+
+        Ok(SparseMerkleTree {
+            tree,
+            empty_hashes: Arc::new(empty_hashes),
+            dirty,
+            present,
+            region: None,
+            presence: None,
+            root,
+            marker: PhantomData,
+        })
+    }
+
+    /// Give the path leading from the leaf at `index` up to the root.  This is
+    /// a "proof" in the sense of "valid path in a Merkle tree", not a ZK
+    /// argument. Fails with `MerkleError::IndexOutOfRange` unless `index` is a
+    /// leaf of the tree.
+    pub fn generate_membership_path(&self, index: u64) -> Result<Path<F, N>, Error> {
+        check_range::<N>(index)?;
+        let mut path = heapless::Vec::new();
+
+        let tree_index = convert_index_to_last_level(index, N);
+
+        // Iterate from the leaf up to the root, storing all intermediate hash values.
+        let mut current_node = tree_index;
+        while !is_root(current_node) {
+            let sibling_node = sibling(current_node).unwrap();
+
+            let current = self.node(current_node);
+            let sibling = self.node(sibling_node);
+
+            let pair = if is_left_child(current_node) {
+                (current, sibling)
             } else {
-                bail!("parent not found");
+                (sibling, current)
+            };
+            try_push(&mut path, pair)?;
+            current_node = parent(current_node).unwrap();
+        }
+
+        Ok(Path { path })
+    }
+
+    /// Returns the sibling at `level` on the path from the leaf at `index`,
+    /// the same node `generate_membership_path` puts in the pair at `level`.
+    /// Level 0 is the sibling of the leaf itself.
+    pub fn sibling_at(&self, index: u64, level: usize) -> Result<F, Error> {
+        check_range::<N>(index)?;
+        if level >= N {
+            return Err(MerkleError::LevelOutOfRange { level, height: N }.into());
+        }
+
+        let mut current_node = convert_index_to_last_level(index, N);
+        for _ in 0..level {
+            current_node = parent(current_node).unwrap();
+        }
+        let sibling_node = sibling(current_node).unwrap();
+
+        Ok(self.node(sibling_node))
+    }
+
+    pub fn generate_membership_proof(&self, index: u64) -> Result<Proof<F, N>, Error> {
+        let path = self.generate_membership_path(index)?;
+        let tree_index = convert_index_to_last_level(index, N);
+
+        Ok(Proof {
+            path,
+            root: self.root(),
+            leaf: self.leaf(tree_index),
+            present: self.binds_presence() && self.is_present(index),
+        })
+    }
+
+    /// Returns a standalone membership proof for each index, in the order of
+    /// `indices`. The sibling pairs of nodes shared by several paths are only
+    /// looked up once. Fails with `MerkleError::IndexOutOfRange` if any index
+    /// is not a leaf of the tree.
+    pub fn generate_membership_proofs(&self, indices: &[u64]) -> Result<Vec<Proof<F, N>>, Error> {
+        // Sibling pairs by parent node index.
+        let mut pairs: BTreeMap<u64, (F, F)> = BTreeMap::new();
+        let mut proofs = Vec::with_capacity(indices.len());
+        for index in indices {
+            check_range::<N>(*index)?;
+            let tree_index = convert_index_to_last_level(*index, N);
+            let mut path = heapless::Vec::new();
+            let mut current_node = tree_index;
+            while let Some(parent_node) = parent(current_node) {
+                let pair = pairs.entry(parent_node).or_insert_with(|| {
+                    (self.node(left_child(parent_node)), self.node(right_child(parent_node)))
+                });
+                try_push(&mut path, pair.clone())?;
+                current_node = parent_node;
             }
+
+            proofs.push(Proof {
+                path: Path { path },
+                root: self.root(),
+                leaf: self.leaf(tree_index),
+                present: self.binds_presence() && self.is_present(*index),
+            });
         }
 
-        for level in 0..(N - 1) {
-            let mut new_idxs: BTreeSet<u64> = BTreeSet::new();
-            let empty_hash_parent = self.empty_hashes[level + 1].clone();
-            let empty_hash = self.empty_hashes[level].clone();
-            // Each layer is only calculated once
-            for i in level_idxs {
-                let left_index = left_child(i);
-                let right_index = right_child(i);
-                let left = self.tree.get(&left_index).unwrap_or(&empty_hash);
-                let right = self.tree.get(&right_index).unwrap_or(&empty_hash);
+        Ok(proofs)
+    }
 
-                let got = *self.tree.get(&i).unwrap_or(&empty_hash_parent);
-                let expected = hasher.hash([left.clone(), right.clone()])?;
-                assert!(expected == got);
+    /// Proves that the leaves at `left_index` and `left_index + 1` are the two
+    /// children of the same node.
+    pub fn prove_siblings(&self, left_index: u64) -> Result<SiblingProof<F, N>, Error> {
+        check_range::<N>(left_index)?;
+        if self.binds_presence() {
+            bail!("sibling proofs of a tree that binds presence are not supported");
+        }
+        let true_index = convert_index_to_last_level(left_index, N);
+        if !is_left_child(true_index) {
+            return Err(MerkleError::NotLeftChild { index: left_index }.into());
+        }
 
-                let parent = match parent(i) {
-                    Some(i) => i,
-                    None => break,
-                };
-                new_idxs.insert(parent);
+        let full = self.generate_membership_path(left_index)?;
+        let (left, right) = full.path[0].clone();
+        let mut path = heapless::Vec::new();
+        for pair in &full.path[1..] {
+            try_push(&mut path, pair.clone())?;
+        }
+        let parent = match parent(true_index) {
+            Some(i) if is_root(i) => self.root(),
+            Some(i) => self.node(i),
+            None => return Err(MerkleError::InvalidPathNodes.into()),
+        };
+
+        Ok(SiblingProof {
+            index: left_index,
+            left,
+            right,
+            parent,
+            path: Path { path },
+            root: self.root(),
+        })
+    }
+
+    /// Returns the membership proof of the leaf at `index` after checking that
+    /// the leaf is the hash of `secret`.
+    pub fn prove_value_preimage(
+        &self,
+        index: u64,
+        secret: &[u8],
+        hasher: &H,
+    ) -> Result<Proof<F, N>, Error>
+    where
+        H: BytesHasher<F>,
+    {
+        let proof = self.generate_membership_proof(index)?;
+        if proof.leaf != hasher.hash_bytes(secret)? {
+            return Err(MerkleError::InvalidLeaf.into());
+        }
+
+        Ok(proof)
+    }
+
+    /// Leaves as in leaf in index in the leaf vector. Duplicate indices are
+    /// proven once.
+    pub fn batch_prove(&self, leaves: &[u64]) -> PartialTree<F, N> {
+        self.batch_prove_with_absent(leaves, &[])
+    }
+
+    /// Like `batch_prove`, but additionally witnesses that every index in
+    /// `absent` holds the empty leaf.
+    pub fn batch_prove_with_absent(&self, leaves: &[u64], absent: &[u64]) -> PartialTree<F, N> {
+        self.prove_paths(leaves, absent, None)
+    }
+
+    /// Like `batch_prove`, but also returns the cost of the proof, counted
+    /// while the paths are collected.
+    pub fn batch_prove_with_stats(&self, leaves: &[u64]) -> (PartialTree<F, N>, ProofStats) {
+        let mut interior = BTreeSet::new();
+        let partial = self.prove_paths(leaves, &[], Some(&mut interior));
+
+        let element = std::mem::size_of::<F>();
+        let indices = partial.leaves.len() + partial.absent.len() + partial.present.len();
+        let region = partial.region.as_ref().map_or(0, |_| 16 + element);
+        // Deriving the region's empty nodes takes one hash per level.
+        let region_hashes = partial.region.as_ref().map_or(0, |_| N);
+        let stats = ProofStats {
+            nodes: partial.tree.len(),
+            hashes: N + region_hashes + interior.len() + partial.present.len(),
+            size: 4
+                + 4
+                + partial.tree.len() * (8 + element)
+                + element
+                + 3 * 4
+                + indices * 8
+                + 4
+                + N * element
+                + 4
+                + region,
+        };
+
+        (partial, stats)
+    }
+
+    /// Collects the paths of `leaves` and `absent` into a partial tree. The
+    /// tree indices of the interior nodes on the paths are added to
+    /// `interior`, if given.
+    fn prove_paths(
+        &self,
+        leaves: &[u64],
+        absent: &[u64],
+        mut interior: Option<&mut BTreeSet<u64>>,
+    ) -> PartialTree<F, N> {
+        let mut partial = PartialTree {
+            empty_hashes: (*self.empty_hashes).clone(),
+            region: self.empty_region().cloned(),
+            root: self.root(),
+            ..Default::default()
+        };
+
+        for leaf in leaves.iter().chain(absent) {
+            self.add_path(&mut partial, *leaf, interior.as_deref_mut());
+        }
+        partial.leaves.extend(leaves);
+        partial.absent.extend(absent);
+        partial.canonicalize();
+        // The paths hold the nodes of the leaves, the partial tree holds the
+        // values of the proven ones.
+        if self.binds_presence() {
+            for leaf in &partial.leaves {
+                if self.is_present(*leaf) {
+                    partial.present.push(*leaf);
+                    let tree_index = convert_index_to_last_level(*leaf, N);
+                    partial.tree.insert(tree_index, self.leaf(tree_index));
+                }
             }
-            level_idxs = new_idxs;
         }
 
-        Ok(())
+        partial
     }
-}
 
-/// A function to generate empty hashes with a given `default_leaf`.
-///
-/// Given a `FieldHasher`, generate a list of `N` hashes consisting
-/// of the `default_leaf` hashed with itself and repeated `N` times
-/// with the intermediate results. These are used to initialize the
-/// sparse portion of the Sparse Merkle Tree.
-pub fn gen_empty_hashes<F: FieldExt, H: FieldHasher<F, 2>, const N: usize>(
-    hasher: &H,
-    mut default_leaf: F,
-) -> Result<heapless::Vec<F, N>, Error> {
-    let mut empty_hashes = heapless::Vec::new();
-    let mut item;
-    for ix in 0..N {
-        item = default_leaf;
-        let _ = empty_hashes.push(item);
-        default_leaf = hasher.hash([default_leaf, default_leaf])?;
+    /// Stores the non-empty nodes on the path of `leaf` and their siblings into
+    /// `partial`.
+    fn add_path(
+        &self,
+        partial: &mut PartialTree<F, N>,
+        leaf: u64,
+        mut interior: Option<&mut BTreeSet<u64>>,
+    ) {
+        let tree_index = convert_index_to_last_level(leaf, N);
+
+        // Iterate from the leaf up to the root, storing all intermediate hash values.
+        let mut current_node = tree_index;
+
+        while !is_root(current_node) {
+            let sibling_node = sibling(current_node).unwrap();
+
+            for node in [current_node, sibling_node] {
+                if let Some(value) = self.stored_node(node) {
+                    if *value != self.empty_node(node) {
+                        partial.tree.insert(node, value.clone());
+                    }
+                }
+            }
+
+            current_node = parent(current_node).unwrap();
+            if let Some(interior) = interior.as_deref_mut() {
+                interior.insert(current_node);
+            }
+        }
     }
-    assert!(empty_hashes.len() == N);
 
-    Ok(empty_hashes)
-}
+    /// Applies `updates`, a map from leaf indices to new leaf values, and
+    /// returns a proof binding the old root to the new root.
+    ///
+    /// Pending inserts are flushed before the old root is taken. An index out
+    /// of range fails with `MerkleError::IndexOutOfRange` before anything is
+    /// flushed or updated.
+    pub fn update_batch(
+        &mut self,
+        updates: &BTreeMap<u64, F>,
+        hasher: &H,
+    ) -> Result<BatchUpdateProof<F, N>, Error> {
+        if self.region.is_some() {
+            bail!("batch update proofs of a tree with an empty region are not supported");
+        }
+        if self.binds_presence() {
+            bail!("batch update proofs of a tree that binds presence are not supported");
+        }
+        for index in updates.keys() {
+            check_range::<N>(*index)?;
+        }
+        self.flush(hasher)?;
 
-fn convert_index_to_last_level(index: u64, height: usize) -> u64 {
-    index + (1u64 << height) - 1
-}
+        let mut proof = BatchUpdateProof {
+            siblings: BTreeMap::new(),
+            old_leaves: BTreeMap::new(),
+            new_leaves: updates.clone(),
+            empty_hashes: (*self.empty_hashes).clone(),
+            old_root: self.root(),
+            new_root: self.root(),
+        };
 
-/// Returns the log2 value of the given number.
-#[inline]
-fn log2(number: u64) -> u32 {
-    ark_std::log2(number as usize)
-}
+        // All nodes on an updated path, the siblings of these that are not on
+        // an updated path themselves are the authentication nodes.
+        let mut on_path: BTreeSet<u64> = BTreeSet::new();
+        for index in updates.keys() {
+            let tree_index = convert_index_to_last_level(*index, N);
+            let leaf = self.tree.get(&tree_index).unwrap_or(&self.empty_hashes[0]);
+            proof.old_leaves.insert(*index, leaf.clone());
 
-/// Returns the height of the tree, given the size of the tree.
-#[inline]
-fn tree_height(tree_size: u64) -> u32 {
-    log2(tree_size)
+            let mut current_node = tree_index;
+            while !is_root(current_node) {
+                on_path.insert(current_node);
+                current_node = parent(current_node).unwrap();
+            }
+        }
+        for node in &on_path {
+            let sibling_node = sibling(*node).unwrap();
+            if on_path.contains(&sibling_node) {
+                continue;
+            }
+            if let Some(sibling) = self.tree.get(&sibling_node) {
+                proof.siblings.insert(sibling_node, sibling.clone());
+            }
+        }
+
+        for (index, leaf) in updates {
+            self.insert(*index, leaf.clone())?;
+        }
+        self.flush(hasher)?;
+        proof.new_root = self.root();
+
+        Ok(proof)
+    }
 }
 
-/// Returns true iff the index represents the root.
-#[inline]
-fn is_root(index: u64) -> bool {
-    index == 0
+/// An immutable snapshot of a `SparseMerkleTree`, see `freeze`.
+///
+/// Clones share the tree through an `Arc`, and the snapshot is `Send + Sync`
+/// when the leaves and the hasher type are, so it can be handed to several
+/// threads to generate proofs without a lock.
+pub struct FrozenSmt<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> {
+    inner: Arc<SparseMerkleTree<F, H, N>>,
 }
 
-/// Returns the index of the left child, given an index.
-#[inline]
-fn left_child(index: u64) -> u64 {
-    2 * index + 1
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> Clone for FrozenSmt<F, H, N> {
+    fn clone(&self) -> Self {
+        FrozenSmt {
+            inner: Arc::clone(&self.inner),
+        }
+    }
 }
 
-/// Returns the index of the right child, given an index.
-#[inline]
-fn right_child(index: u64) -> u64 {
-    2 * index + 2
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> SparseMerkleTree<F, H, N> {
+    /// Turns the tree into a read-only snapshot that can be shared between
+    /// threads. Flush pending inserts first, the snapshot can't flush them.
+    pub fn freeze(self) -> FrozenSmt<F, H, N> {
+        FrozenSmt {
+            inner: Arc::new(self),
+        }
+    }
 }
 
-/// Returns the index of the sibling, given an index.
-#[inline]
-fn sibling(index: u64) -> Option<u64> {
-    if index == 0 {
-        None
-    } else if is_left_child(index) {
-        Some(index + 1)
-    } else {
-        Some(index - 1)
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> FrozenSmt<F, H, N> {
+    /// Returns the Merkle tree root as of the last flush before freezing.
+    pub fn root(&self) -> F {
+        self.inner.root()
+    }
+
+    /// Returns the leaf stored at `index`. `None` means the leaf was never
+    /// set, or was deleted, or `index` is out of range. A leaf set to the empty
+    /// leaf is stored, so it is returned like any other value.
+    pub fn get(&self, index: u64) -> Option<&F> {
+        if index >= self.inner.capacity() {
+            return None;
+        }
+        self.inner.tree.get(&convert_index_to_last_level(index, N))
+    }
+
+    pub fn generate_membership_proof(&self, index: u64) -> Result<Proof<F, N>, Error> {
+        self.inner.generate_membership_proof(index)
+    }
+
+    pub fn batch_prove(&self, leaves: &[u64]) -> PartialTree<F, N> {
+        self.inner.batch_prove(leaves)
     }
 }
 
-/// Returns true iff the given index represents a left child.
-#[inline]
-fn is_left_child(index: u64) -> bool {
-    index % 2 == 1
+/// A set of trees of the same height and empty leaf, by id.
+///
+/// The empty hash chain and the empty root are computed once, and every tree
+/// of the forest shares the same chain.
+pub struct Forest<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> {
+    empty_hashes: Arc<heapless::Vec<F, N>>,
+    empty_root: F,
+    trees: BTreeMap<u64, SparseMerkleTree<F, H, N>>,
 }
 
-/// Returns the index of the parent, given an index.
-#[inline]
-fn parent(index: u64) -> Option<u64> {
-    if index > 0 {
-        Some((index - 1) >> 1)
-    } else {
-        None
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> Forest<F, H, N> {
+    pub fn new(hasher: &H, empty_leaf: F) -> Result<Self, Error> {
+        let empty_hashes: heapless::Vec<F, N> = gen_empty_hashes(hasher, empty_leaf)?;
+        let top = empty_hashes[N - 1].clone();
+        let empty_root = hasher.hash([top.clone(), top])?;
+
+        Ok(Forest {
+            empty_hashes: Arc::new(empty_hashes),
+            empty_root,
+            trees: BTreeMap::new(),
+        })
     }
-}
 
-use sha2::digest::Update;
-use sha2::Sha256;
+    /// Returns the tree with the given id, if `insert` was called for it.
+    pub fn tree(&self, id: u64) -> Option<&SparseMerkleTree<F, H, N>> {
+        self.trees.get(&id)
+    }
 
-pub type BYTE32 = [u8; 32];
+    /// Inserts `leaves` in the tree with the given id, which starts out empty.
+    pub fn insert(&mut self, id: u64, leaves: &BTreeMap<u32, F>, hasher: &H) -> Result<(), Error> {
+        let empty_hashes = &self.empty_hashes;
+        let empty_root = self.empty_root;
+        let tree = self.trees.entry(id).or_insert_with(|| SparseMerkleTree {
+            tree: BTreeMap::new(),
+            empty_hashes: Arc::clone(empty_hashes),
+            dirty: BTreeSet::new(),
+            present: BTreeSet::new(),
+            region: None,
+            presence: None,
+            root: empty_root,
+            marker: PhantomData,
+        });
 
-impl FieldExt for [u8; 32] {}
+        tree.try_insert_batch(leaves, hasher)
+    }
 
-impl<const N: usize> FieldHasher<[u8; 32], N> for Sha256 {
-    fn hash(&self, nodes: [[u8; 32]; N]) -> Result<[u8; 32]> {
-        let mut h = Sha256::new();
-        for n in nodes {
-            Update::update(&mut h, &n);
-        }
-        let f = h.finalize().to_vec();
-        let mut s32 = [0; 32];
-        s32.copy_from_slice(&f);
-        Ok(s32)
+    /// Returns the empty hash of each level, starting with the empty leaf.
+    pub fn empty_hashes(&self) -> &[F] {
+        &self.empty_hashes
     }
 }
 
-#[cfg(test)]
-mod test {
+/// A proof that updating a set of leaves from their old to their new values
+/// takes a tree from `old_root` to `new_root`.
+///
+/// Both roots are reconstructed from the same authentication nodes, so the
+/// leaves outside the updated set are unchanged by the transition.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BatchUpdateProof<F: FieldExt, const N: usize> {
+    /// The non-empty siblings of the updated paths, by tree index.
+    pub siblings: BTreeMap<u64, F>,
+    /// The leaf values before the update, by map index.
+    pub old_leaves: BTreeMap<u64, F>,
+    /// The leaf values after the update, by map index.
+    pub new_leaves: BTreeMap<u64, F>,
+    empty_hashes: heapless::Vec<F, N>,
+    pub old_root: F,
+    pub new_root: F,
+}
+
+impl<F: FieldExt + Debug, const N: usize> BatchUpdateProof<F, N> {
+    /// Checks that the old and the new leaves reconstruct the old and the new
+    /// root respectively.
+    pub fn verify<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<(), Error> {
+        if !self.old_leaves.keys().eq(self.new_leaves.keys()) {
+            return Err(MerkleError::InvalidLeaf.into());
+        }
+        let old_root =
+            fold_root(&self.siblings, &self.old_leaves, &self.empty_hashes, None, hasher)?;
+        let new_root =
+            fold_root(&self.siblings, &self.new_leaves, &self.empty_hashes, None, hasher)?;
+        for (computed, expected) in [(old_root, self.old_root), (new_root, self.new_root)] {
+            if computed != expected {
+                return Err(MerkleError::RootMismatch {
+                    computed: format!("{:?}", computed),
+                    expected: format!("{:?}", expected),
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Computes the root from `leaves`, a map from leaf indices to leaf values,
+/// and the authentication nodes in `siblings`. Absent siblings are empty.
+fn fold_root<F: FieldExt, H: FieldHasher<F, 2>, const N: usize>(
+    siblings: &BTreeMap<u64, F>,
+    leaves: &BTreeMap<u64, F>,
+    empty_hashes: &heapless::Vec<F, N>,
+    region: Option<&RegionHashes<F, N>>,
+    hasher: &H,
+) -> Result<F, Error> {
+    let nodes = fold_nodes(siblings, leaves, empty_hashes, region, hasher)?;
+    match nodes.get(&0) {
+        Some(root) => Ok(root.clone()),
+        None => Err(MerkleError::InvalidPathNodes.into()),
+    }
+}
+
+/// Like `fold_root`, but returns every node computed on the way up, by tree
+/// index, the leaves and the root included.
+fn fold_nodes<F: FieldExt, H: FieldHasher<F, 2>, const N: usize>(
+    siblings: &BTreeMap<u64, F>,
+    leaves: &BTreeMap<u64, F>,
+    empty_hashes: &heapless::Vec<F, N>,
+    region: Option<&RegionHashes<F, N>>,
+    hasher: &H,
+) -> Result<BTreeMap<u64, F>, Error> {
+    if empty_hashes.len() != N {
+        return Err(MerkleError::InvalidEmptyHashes {
+            len: empty_hashes.len(),
+            height: N,
+        }
+        .into());
+    }
+    if leaves.is_empty() {
+        return Err(MerkleError::InvalidPathNodes.into());
+    }
+
+    let mut nodes: BTreeMap<u64, F> = BTreeMap::new();
+    let mut level_idxs: BTreeSet<u64> = BTreeSet::new();
+    for (i, leaf) in leaves {
+        if *i >= 1u64 << N {
+            return Err(MerkleError::IndexOutOfRange {
+                index: *i,
+                capacity: 1u64 << N,
+            }
+            .into());
+        }
+        let true_index = convert_index_to_last_level(*i, N);
+        nodes.insert(true_index, leaf.clone());
+        match parent(true_index) {
+            Some(idx) => level_idxs.insert(idx),
+            None => return Err(MerkleError::IndexOutOfRange { index: *i, capacity: 1 }.into()),
+        };
+    }
+
+    let node = |nodes: &BTreeMap<u64, F>, index: u64| {
+        match nodes.get(&index).or(siblings.get(&index)) {
+            Some(node) => node.clone(),
+            None => empty_node(index, empty_hashes, region),
+        }
+    };
+    for _ in 0..N {
+        let mut new_idxs: BTreeSet<u64> = BTreeSet::new();
+        for i in level_idxs {
+            let left = node(&nodes, left_child(i));
+            let right = node(&nodes, right_child(i));
+            let hash = hasher.hash([left, right])?;
+            nodes.insert(i, hash);
+
+            if let Some(parent) = parent(i) {
+                new_idxs.insert(parent);
+            }
+        }
+        level_idxs = new_idxs;
+    }
+
+    Ok(nodes)
+}
+
+/// The cost of a batch proof, see `SparseMerkleTree::batch_prove_with_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProofStats {
+    /// The number of nodes stored in the partial tree.
+    pub nodes: usize,
+    /// The number of hashes `PartialTree::verify` computes: the empty hash
+    /// chain, the empty nodes of the region if any, then each interior node on
+    /// the proven paths once.
+    pub hashes: usize,
+    /// The length of `PartialTree::to_bytes` for 32-byte elements, estimated
+    /// from the in-memory size of `F` for other elements.
+    pub size: usize,
+}
+
+/// A range of leaves whose unset leaves hold `empty_leaf` rather than the
+/// tree's empty leaf, see `SparseMerkleTree::with_empty_region`.
+///
+/// The range `start..end` must span a subtree below the root: its length is
+/// a power of two, at most half the leaves, and `start` is a multiple of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EmptyRegion<F: FieldExt> {
+    /// The first map index of the region.
+    pub start: u64,
+    /// One past the last map index of the region.
+    pub end: u64,
+    /// The value of the unset leaves of the region.
+    pub empty_leaf: F,
+}
+
+/// The empty nodes of a tree with an `EmptyRegion`.
+#[derive(Clone, Debug)]
+struct RegionHashes<F: FieldExt, const N: usize> {
+    region: EmptyRegion<F>,
+    /// The tree index of the root of the region.
+    node: u64,
+    /// The level of `node`.
+    level: usize,
+    /// The empty node of each level of the region, `0..=level`.
+    empty_hashes: heapless::Vec<F, N>,
+    /// The empty node of each ancestor of `node`, `level + 1..=N`, the root
+    /// last.
+    ancestors: heapless::Vec<F, N>,
+}
+
+impl<F: FieldExt, const N: usize> RegionHashes<F, N> {
+    /// Derives the empty nodes of `region` in a tree whose other empty nodes
+    /// are `base`.
+    fn new<H: FieldHasher<F, 2>>(region: EmptyRegion<F>, base: &[F], hasher: &H) -> Result<Self> {
+        let (node, level) = region_root::<F, N>(&region)?;
+        let mut empty_hashes = heapless::Vec::new();
+        let mut hash = region.empty_leaf.clone();
+        for _ in 0..level {
+            try_push(&mut empty_hashes, hash.clone())?;
+            hash = hasher.hash([hash.clone(), hash])?;
+        }
+        try_push(&mut empty_hashes, hash.clone())?;
+
+        let mut ancestors = heapless::Vec::new();
+        let mut index = node;
+        for sibling in &base[level..N] {
+            hash = if is_left_child(index) {
+                hasher.hash([hash, sibling.clone()])?
+            } else {
+                hasher.hash([sibling.clone(), hash])?
+            };
+            try_push(&mut ancestors, hash.clone())?;
+            index = (index - 1) >> 1;
+        }
+
+        Ok(RegionHashes {
+            region,
+            node,
+            level,
+            empty_hashes,
+            ancestors,
+        })
+    }
+
+    /// Returns the value of the node at tree index `index` when no leaf below
+    /// it is set.
+    fn empty_node(&self, index: u64, base: &[F]) -> F {
+        let level = node_level(index, N);
+        if is_below::<N>(index, self.node, self.level) {
+            self.empty_hashes[level].clone()
+        } else if level > self.level && is_below::<N>(self.node, index, level) {
+            self.ancestors[level - self.level - 1].clone()
+        } else {
+            base[level].clone()
+        }
+    }
+}
+
+// Partial tree
+// Turn Vec<Path> Into a partial tree. Verify tree.
+
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PartialTree<F: FieldExt, const N: usize> {
+    pub tree: BTreeMap<u64, F>,
+    empty_hashes: heapless::Vec<F, N>,
+    /// as in map index. not tree index
+    pub leaves: Vec<u64>,
+    /// Map indices that must hold the empty leaf.
+    pub absent: Vec<u64>,
+    /// The proven leaves that were inserted, as opposed to never set, in a
+    /// tree built with `SparseMerkleTree::with_presence`. Like
+    /// `Proof::present`, the root binds them: their nodes are `present_leaf`
+    /// of their values. Indices that are not in `leaves` mean nothing.
+    pub present: Vec<u64>,
+    /// The region of the tree with its own empty leaf, if any. Like the empty
+    /// leaf, it comes from the prover: `verify` checks the absent leaves
+    /// against it, so the verifier must compare it to the region it expects,
+    /// see `ProofClaims::region`.
+    pub region: Option<EmptyRegion<F>>,
+    pub root: F,
+}
+
+impl<F: FieldExt, const N: usize> PartialTree<F, N> {
+    /// Sorts and dedups the leaf index lists, so that equivalent proofs
+    /// serialize to identical bytes. Call this before serializing a partial
+    /// tree that was modified by hand; `batch_prove` already returns one in
+    /// canonical form.
+    pub fn canonicalize(&mut self) {
+        self.leaves.sort_unstable();
+        self.leaves.dedup();
+        self.absent.sort_unstable();
+        self.absent.dedup();
+        self.present.sort_unstable();
+        self.present.dedup();
+    }
+
+    /// Returns the empty hash of each level, starting with the empty leaf.
+    pub fn empty_hashes(&self) -> &[F] {
+        &self.empty_hashes
+    }
+
+    /// Drops the nodes that are not needed to verify the paths of `leaves` and
+    /// `absent`, e.g. after `leaves` was narrowed down, and the `present`
+    /// indices that are no longer proven. Nodes holding the
+    /// empty hash of their level are dropped too, as a missing node is empty.
+    /// So are nodes that don't fit in a tree of height `N`, which may come
+    /// from an untrusted source.
+    pub fn prune(&mut self) {
+        let () = Height::<N>::CHECK;
+        let mut needed: BTreeSet<u64> = BTreeSet::new();
+        for i in self.leaves.iter().chain(&self.absent) {
+            if *i >= 1u64 << N {
+                continue;
+            }
+            let mut node = convert_index_to_last_level(*i, N);
+            while let (Some(sibling), Some(parent)) = (sibling(node), parent(node)) {
+                needed.insert(node);
+                needed.insert(sibling);
+                node = parent;
+            }
+        }
+
+        let leaves = &self.leaves;
+        self.present.retain(|i| leaves.contains(i));
+        let empty_hashes = &self.empty_hashes;
+        let region = match &self.region {
+            Some(region) => region_root::<F, N>(region).ok().map(|r| (r, region.empty_leaf)),
+            None => None,
+        };
+        self.tree.retain(|i, node| {
+            // Only nodes on a path are needed, and those are in range.
+            if !needed.contains(i) {
+                return false;
+            }
+            let level = node_level(*i, N);
+            let empty = match region {
+                // The empty nodes above the region's leaves are unknown
+                // without hashing, those are kept.
+                Some(((root, root_level), empty_leaf))
+                    if is_below::<N>(*i, root, root_level) || is_below::<N>(root, *i, level) =>
+                {
+                    Some(empty_leaf).filter(|_| level == 0)
+                }
+                _ => empty_hashes.get(level).cloned(),
+            };
+            empty.as_ref() != Some(node)
+        });
+    }
+
+    /// Checks the shape of a partial tree received from an untrusted source,
+    /// before `verify` indexes into it: every node fits in a tree of height
+    /// `N`, the proven and absent leaves are in range and listed once, and
+    /// there is an empty hash for each level.
+    ///
+    /// Called by `verify` and `compute_root`, it doesn't hash anything.
+    pub fn validate_structure(&self) -> Result<()> {
+        let () = Height::<N>::CHECK;
+        if self.empty_hashes.len() != N {
+            return Err(MerkleError::InvalidEmptyHashes {
+                len: self.empty_hashes.len(),
+                height: N,
+            }
+            .into());
+        }
+        // 2^(N+1) - 1, which doesn't overflow for N = 63.
+        let node_count = u64::MAX >> (63 - N);
+        if let Some(index) = self.tree.keys().find(|i| **i >= node_count) {
+            return Err(MerkleError::NodeOutOfRange { index: *index }.into());
+        }
+        if let Some(region) = &self.region {
+            region_root::<F, N>(region)?;
+        }
+        let mut seen: BTreeSet<u64> = BTreeSet::new();
+        for i in self.leaves.iter().chain(&self.absent) {
+            if *i >= 1u64 << N {
+                return Err(MerkleError::IndexOutOfRange {
+                    index: *i,
+                    capacity: 1u64 << N,
+                }
+                .into());
+            }
+            if !seen.insert(*i) {
+                return Err(MerkleError::DuplicateLeaf { index: *i }.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the root reconstructed from the proven and absent leaves and the
+    /// stored authentication nodes, without checking the stored interior nodes
+    /// or comparing it to `root`.
+    pub fn compute_root<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<F, Error> {
+        self.validate_structure()?;
+        self.root_from_leaves(hasher)
+    }
+
+    /// `compute_root` for a tree whose structure was validated already.
+    fn root_from_leaves<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<F, Error> {
+        let region = self.region_hashes(hasher)?;
+        let leaves = self.leaf_nodes(hasher)?;
+        fold_root(&self.tree, &leaves, &self.empty_hashes, region.as_ref(), hasher)
+    }
+
+    /// Derives the empty nodes of the region from its empty leaf. Call after
+    /// `validate_structure`.
+    fn region_hashes<H: FieldHasher<F, 2>>(
+        &self,
+        hasher: &H,
+    ) -> Result<Option<RegionHashes<F, N>>> {
+        self.region
+            .as_ref()
+            .map(|region| RegionHashes::new(region.clone(), &self.empty_hashes, hasher))
+            .transpose()
+    }
+
+    /// Returns the value of the leaf at map index `index` while it is unset.
+    fn empty_leaf(&self, index: u64) -> &F {
+        match &self.region {
+            Some(region) if (region.start..region.end).contains(&index) => &region.empty_leaf,
+            _ => &self.empty_hashes[0],
+        }
+    }
+
+    /// The node of each proven and absent leaf, by map index: its value, or
+    /// `present_leaf` of it for a present leaf. Absent leaves are read from
+    /// the tree as well, `verify` checks they are empty.
+    fn leaf_nodes<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<BTreeMap<u64, F>> {
+        let present: BTreeSet<u64> = self.present.iter().copied().collect();
+        let proven = self.leaves.iter().map(|i| (i, present.contains(i)));
+        proven
+            .chain(self.absent.iter().map(|i| (i, false)))
+            .map(|(i, present)| {
+                let true_index = convert_index_to_last_level(*i, N);
+                let leaf = self.tree.get(&true_index).unwrap_or(self.empty_leaf(*i));
+                let node = if present { present_leaf(leaf, hasher)? } else { leaf.clone() };
+                Ok((*i, node))
+            })
+            .collect()
+    }
+
+    /// Returns the `(map index, value)` pair of each proven leaf, in the order
+    /// of `leaves`. Leaves missing from the tree hold the empty leaf.
+    pub fn leaf_values(&self) -> Vec<(u64, F)> {
+        self.leaves
+            .iter()
+            .map(|i| {
+                let true_index = convert_index_to_last_level(*i, N);
+                let leaf = self.tree.get(&true_index).unwrap_or(self.empty_leaf(*i));
+                (*i, leaf.clone())
+            })
+            .collect()
+    }
+}
+
+impl<F: FieldExt + Debug, const N: usize> PartialTree<F, N> {
+    /// Checks the structure, the empty hash chain and the absent leaves, which
+    /// both `verify` and `verify_light` rely on.
+    fn check_leaves<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<()> {
+        self.validate_structure()?;
+
+        // The empty hashes must be the chain derived from the empty leaf,
+        // otherwise a prover could pick arbitrary values for empty subtrees.
+        let empty_leaf = self.empty_hashes[0].clone();
+        let chain: heapless::Vec<F, N> = gen_empty_hashes(hasher, empty_leaf)?;
+        if let Some(level) = (0..N).find(|l| self.empty_hashes.get(*l) != chain.get(*l)) {
+            return Err(MerkleError::EmptyHashMismatch { level }.into());
+        }
+
+        for i in &self.absent {
+            let true_index = convert_index_to_last_level(*i, N);
+            if let Some(leaf) = self.tree.get(&true_index) {
+                if leaf != self.empty_leaf(*i) {
+                    return Err(MerkleError::NonEmptyLeaf { index: *i }.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the leaves fold up to `root`, without checking the stored
+    /// interior nodes against their children.
+    ///
+    /// This proves the same about the leaves as `verify`: the proven values
+    /// are in the tree with root `root`, and the absent leaves are empty. But
+    /// the stored interior nodes on the paths are ignored rather than
+    /// authenticated, so they must not be read from a tree that was only
+    /// verified this way. It saves a comparison per path node, and lets the
+    /// prover leave out the nodes the verifier recomputes anyway.
+    pub fn verify_light<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<()> {
+        self.check_leaves(hasher)?;
+        if self.leaves.is_empty() && self.absent.is_empty() {
+            return Ok(());
+        }
+
+        // check_leaves validated the structure.
+        let computed = self.root_from_leaves(hasher)?;
+        if computed != self.root {
+            return Err(MerkleError::RootMismatch {
+                computed: format!("{:?}", computed),
+                expected: format!("{:?}", self.root),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Like `verify`, but keeps checking past the first mismatch, and fails
+    /// with `VerifyError::Mismatches` listing every `(index, expected, got)`
+    /// node that differs from the one recomputed from the leaves, bottom up.
+    /// `got` is the stored node, or the empty hash if it is missing. A wrong
+    /// root comes last, at index 0, with the claimed root as `got`.
+    ///
+    /// A malformed tree, e.g. with a broken empty hash chain, fails with
+    /// `VerifyError::Structure`, as there are no nodes to compare. `Ok` means
+    /// the tree verifies.
+    pub fn verify_collect<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<(), VerifyError<F>> {
+        match self.mismatches(hasher) {
+            Ok(mismatches) if mismatches.is_empty() => Ok(()),
+            Ok(mismatches) => Err(VerifyError::Mismatches(mismatches)),
+            Err(err) => Err(VerifyError::Structure(err)),
+        }
+    }
+
+    /// The mismatches `verify_collect` reports, empty if the tree verifies.
+    fn mismatches<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<Vec<(u64, F, F)>> {
+        self.check_leaves(hasher)?;
+        // Nothing is proven, there is no path to check the root against.
+        if self.leaves.is_empty() && self.absent.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Each node on the paths is calculated once, from the leaves up. The
+        // stored interior nodes must match, bottom up. Missing ones are empty.
+        let region = self.region_hashes(hasher)?;
+        let region = region.as_ref();
+        let leaves = self.leaf_nodes(hasher)?;
+        let nodes = fold_nodes(&self.tree, &leaves, &self.empty_hashes, region, hasher)?;
+        let mut mismatches = vec![];
+        for (i, expected) in nodes.iter().rev() {
+            if node_level(*i, N) == 0 || is_root(*i) {
+                continue;
+            }
+            let got = match self.tree.get(i) {
+                Some(node) => node.clone(),
+                None => empty_node(*i, &self.empty_hashes, region),
+            };
+            if got != *expected {
+                mismatches.push((*i, expected.clone(), got.clone()));
+            }
+        }
+
+        // The root is not stored in the tree, it is checked against the
+        // claimed root instead.
+        let computed = nodes[&0];
+        if computed != self.root {
+            mismatches.push((0, computed, self.root.clone()));
+        }
+
+        Ok(mismatches)
+    }
+
+    pub fn verify<H: FieldHasher<F, 2>>(&self, hasher: &H) -> anyhow::Result<()> where {
+        // #[cfg(not(feature = "notzk"))]
+        // {
+        //     use risc0_zkvm::guest::env;
+        //     env::commit(&self.root);
+        //     env::log("commited partial tree");
+        // }
+
+        #[cfg(feature = "notzk")]
+        {
+            println!(
+                "Tree proof, total elements {}, leaves {}",
+                self.tree.len(),
+                self.leaves.len()
+            )
+        }
+
+        match self.mismatches(hasher)?.first() {
+            None => {}
+            Some((0, computed, root)) => {
+                return Err(MerkleError::RootMismatch {
+                    computed: format!("{:?}", computed),
+                    expected: format!("{:?}", root),
+                }
+                .into())
+            }
+            Some((index, _, _)) => return Err(MerkleError::NodeMismatch { index: *index }.into()),
+        }
+
+        Ok(())
+    }
+
+    /// Like `verify`, for a partial tree of a tree built with
+    /// `SparseMerkleTree::with_presence`: also requires every proven leaf
+    /// that is not `present` to hold the empty leaf, as the unset leaves of
+    /// such a tree do. Otherwise a prover could leave a leaf out of `present`
+    /// and claim its node as its value.
+    pub fn verify_with_presence<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Result<()> {
+        self.verify(hasher)?;
+        for (i, leaf) in self.leaf_values() {
+            if leaf != *self.empty_leaf(i) && !self.present.contains(&i) {
+                return Err(MerkleError::NonEmptyLeaf { index: i }.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like `verify`, but also requires the root to be `expected_root`, a root
+    /// the verifier trusts, rather than only the prover supplied `root`.
+    pub fn verify_against<H: FieldHasher<F, 2>>(&self, expected_root: &F, hasher: &H) -> Result<()> {
+        self.verify(hasher)?;
+        if self.root != *expected_root {
+            return Err(MerkleError::RootMismatch {
+                computed: format!("{:?}", self.root),
+                expected: format!("{:?}", expected_root),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Binary encoding of a partial tree over 32-byte elements.
+///
+/// All integers are little-endian. The layout is
+///
+/// ```text
+/// u32 length of everything that follows
+/// u32 node count, then per node: u64 tree index, 32-byte hash
+/// 32-byte root
+/// u32 leaf count, then per leaf: u64 map index
+/// u32 absent count, then per absent leaf: u64 map index
+/// u32 present count, then per present leaf: u64 map index
+/// u32 empty hash count, then per level: 32-byte hash
+/// u32 region count, 0 or 1, then per region: u64 start, u64 end, 32-byte
+///     empty leaf
+/// ```
+impl<const N: usize> PartialTree<BYTE32, N> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(self.tree.len() as u32).to_le_bytes());
+        for (index, hash) in &self.tree {
+            body.extend_from_slice(&index.to_le_bytes());
+            body.extend_from_slice(hash);
+        }
+        body.extend_from_slice(&self.root);
+        for indices in [&self.leaves, &self.absent, &self.present] {
+            body.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+            for index in indices {
+                body.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+        body.extend_from_slice(&(self.empty_hashes.len() as u32).to_le_bytes());
+        for hash in &self.empty_hashes {
+            body.extend_from_slice(hash);
+        }
+        body.extend_from_slice(&(self.region.iter().count() as u32).to_le_bytes());
+        if let Some(region) = &self.region {
+            body.extend_from_slice(&region.start.to_le_bytes());
+            body.extend_from_slice(&region.end.to_le_bytes());
+            body.extend_from_slice(&region.empty_leaf);
+        }
+
+        let mut bytes = Vec::with_capacity(4 + body.len());
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader { bytes };
+        let len = reader.u32()? as usize;
+        if reader.bytes.len() != len {
+            bail!("partial tree length prefix does not match the buffer");
+        }
+
+        let mut partial = PartialTree::default();
+        for _ in 0..reader.u32()? {
+            let index = reader.u64()?;
+            partial.tree.insert(index, reader.hash()?);
+        }
+        partial.root = reader.hash()?;
+        for _ in 0..reader.u32()? {
+            partial.leaves.push(reader.u64()?);
+        }
+        for _ in 0..reader.u32()? {
+            partial.absent.push(reader.u64()?);
+        }
+        for _ in 0..reader.u32()? {
+            partial.present.push(reader.u64()?);
+        }
+        for _ in 0..reader.u32()? {
+            try_push(&mut partial.empty_hashes, reader.hash()?)?;
+        }
+        match reader.u32()? {
+            0 => {}
+            1 => {
+                partial.region = Some(EmptyRegion {
+                    start: reader.u64()?,
+                    end: reader.u64()?,
+                    empty_leaf: reader.hash()?,
+                })
+            }
+            n => bail!("{} empty regions, at most one is supported", n),
+        }
+        if !reader.bytes.is_empty() {
+            bail!("trailing bytes after partial tree");
+        }
+
+        Ok(partial)
+    }
+}
+
+/// A one-line summary for logs: the node and leaf counts, the root in hex and
+/// the first few proven leaves. `Debug` prints every node.
+impl<const N: usize> core::fmt::Display for PartialTree<BYTE32, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const SHOWN: usize = 4;
+        write!(
+            f,
+            "PartialTree {{ nodes: {}, leaves: {}, absent: {}, root: 0x",
+            self.tree.len(),
+            self.leaves.len(),
+            self.absent.len()
+        )?;
+        for byte in &self.root {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ", first leaves: {:?}", &self.leaves[..self.leaves.len().min(SHOWN)])?;
+        if self.leaves.len() > SHOWN {
+            write!(f, " and {} more", self.leaves.len() - SHOWN)?;
+        }
+        write!(f, " }}")
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take<const L: usize>(&mut self) -> Result<[u8; L]> {
+        if self.bytes.len() < L {
+            bail!("partial tree is truncated");
+        }
+        let (head, tail) = self.bytes.split_at(L);
+        self.bytes = tail;
+        let mut out = [0; L];
+        out.copy_from_slice(head);
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn hash(&mut self) -> Result<BYTE32> {
+        self.take()
+    }
+}
+
+/// Verifies a batch of independent partial trees, possibly against different
+/// roots, with a single hasher.
+///
+/// Returns the root of each tree, or an error naming the index of the first
+/// tree that fails to verify.
+pub fn verify_many<F: FieldExt + Debug, H: FieldHasher<F, 2>, const N: usize>(
+    trees: &[PartialTree<F, N>],
+    hasher: &H,
+) -> Result<Vec<F>> {
+    let mut roots = Vec::with_capacity(trees.len());
+    for (i, tree) in trees.iter().enumerate() {
+        tree.verify(hasher)
+            .map_err(|e| e.context(format!("partial tree {} failed to verify", i)))?;
+        roots.push(tree.root);
+    }
+
+    Ok(roots)
+}
+
+/// A function to generate empty hashes with a given `default_leaf`.
+///
+/// Given a `FieldHasher`, generate a list of `N` hashes consisting
+/// of the `default_leaf` hashed with itself and repeated `N` times
+/// with the intermediate results. These are used to initialize the
+/// sparse portion of the Sparse Merkle Tree.
+pub fn gen_empty_hashes<F: FieldExt, H: FieldHasher<F, 2>, const N: usize>(
+    hasher: &H,
+    mut default_leaf: F,
+) -> Result<heapless::Vec<F, N>, Error> {
+    let () = Height::<N>::CHECK;
+    let mut empty_hashes = heapless::Vec::new();
+    for _ in 0..N {
+        try_push(&mut empty_hashes, default_leaf)?;
+        default_leaf = hasher.hash([default_leaf, default_leaf])?;
+    }
+
+    Ok(empty_hashes)
+}
+
+/// The node indices of a tree of height `N` only fit in a `u64` for
+/// `N <= 63`. Evaluating `CHECK` fails to compile for taller trees.
+struct Height<const N: usize>;
+
+impl<const N: usize> Height<N> {
+    const CHECK: () = assert!(N <= 63, "the tree height must be at most 63");
+}
+
+/// Fails with `MerkleError::IndexOutOfRange` unless `index` is a leaf of a
+/// tree of height `N`.
+fn check_range<const N: usize>(index: u64) -> Result<(), Error> {
+    let () = Height::<N>::CHECK;
+    if index >= 1u64 << N {
+        return Err(MerkleError::IndexOutOfRange {
+            index,
+            capacity: 1u64 << N,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Pushes `item`, failing with `MerkleError::CapacityExceeded` rather than
+/// dropping it when `vec` is full.
+fn try_push<T, const C: usize>(vec: &mut heapless::Vec<T, C>, item: T) -> Result<(), Error> {
+    vec.push(item).map_err(|_| MerkleError::CapacityExceeded { capacity: C }.into())
+}
+
+/// Returns the node of a leaf inserted with value `value` into a tree built
+/// with `SparseMerkleTree::with_presence`: `value` hashed after the presence
+/// tag `F::default()`.
+pub fn present_leaf<F: FieldExt, H: FieldHasher<F, 2>>(value: &F, hasher: &H) -> Result<F> {
+    hasher.hash([F::default(), value.clone()])
+}
+
+/// Returns the value of the node at tree index `index`, below the root, when
+/// no leaf below it is set.
+fn empty_node<F: FieldExt, const N: usize>(
+    index: u64,
+    empty_hashes: &[F],
+    region: Option<&RegionHashes<F, N>>,
+) -> F {
+    match region {
+        Some(region) => region.empty_node(index, empty_hashes),
+        None => empty_hashes[node_level(index, N)].clone(),
+    }
+}
+
+/// Returns true iff the node at tree index `index` is `node`, which is at
+/// `level`, or one of its descendants, in a tree of height `N`.
+fn is_below<const N: usize>(index: u64, node: u64, level: usize) -> bool {
+    let index_level = node_level(index, N);
+    index_level <= level && (index + 1) >> (level - index_level) == node + 1
+}
+
+/// Returns the tree index and the level of the root of the subtree spanned by
+/// `region`, which must be below the root of a tree of height `N`.
+fn region_root<F: FieldExt, const N: usize>(region: &EmptyRegion<F>) -> Result<(u64, usize)> {
+    let len = region.end.wrapping_sub(region.start);
+    let aligned = region.start < region.end && len.is_power_of_two() && region.start % len == 0;
+    if !aligned || len > 1 << (N - 1) || region.end > 1 << N {
+        return Err(MerkleError::InvalidRegion {
+            start: region.start,
+            end: region.end,
+        }
+        .into());
+    }
+    let level = len.trailing_zeros() as usize;
+
+    Ok((((convert_index_to_last_level(region.start, N) + 1) >> level) - 1, level))
+}
+
+/// Returns the level of the node at tree index `index` in a tree of height
+/// `n`, leaves being at level 0 and the root at level `n`.
+fn node_level(index: u64, n: usize) -> usize {
+    n - (63 - (index + 1).leading_zeros() as usize)
+}
+
+/// Returns the log2 value of the given number.
+#[inline]
+fn log2(number: u64) -> u32 {
+    ark_std::log2(number as usize)
+}
+
+use sha2::digest::Update;
+use sha2::{Sha256, Sha512};
+#[cfg(feature = "keccak")]
+use sha3::Keccak256;
+
+pub type BYTE32 = [u8; 32];
+
+impl FieldExt for [u8; 32] {}
+
+impl<const N: usize> FieldHasher<[u8; 32], N> for Sha256 {
+    fn hash(&self, nodes: [[u8; 32]; N]) -> Result<[u8; 32]> {
+        let mut h = Sha256::new();
+        for n in nodes {
+            Update::update(&mut h, &n);
+        }
+        Ok(h.finalize().into())
+    }
+}
+
+impl BytesHasher<[u8; 32]> for Sha256 {
+    fn hash_bytes(&self, bytes: &[u8]) -> Result<[u8; 32]> {
+        Ok(Sha256::digest(bytes).into())
+    }
+}
+
+/// A hasher over 32-byte elements for any `Digest`. Both nodes are fed into a
+/// fresh digest, and the output is truncated to 32 bytes. Digests with a
+/// shorter output are rejected.
+///
+/// `DigestHasher<Sha256>` hashes like `Sha256`.
+pub struct DigestHasher<D> {
+    marker: PhantomData<fn() -> D>,
+}
+
+impl<D> DigestHasher<D> {
+    pub fn new() -> Self {
+        DigestHasher {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<D> Default for DigestHasher<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Clone for DigestHasher<D> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Copy for DigestHasher<D> {}
+
+impl<D> Debug for DigestHasher<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DigestHasher<{}>", std::any::type_name::<D>())
+    }
+}
+
+impl<D: Digest> DigestHasher<D> {
+    fn truncate(digest: &[u8]) -> Result<BYTE32> {
+        if digest.len() < 32 {
+            bail!("digest output of {} bytes is shorter than 32", digest.len());
+        }
+        let mut s32 = [0; 32];
+        s32.copy_from_slice(&digest[..32]);
+        Ok(s32)
+    }
+}
+
+impl<D: Digest> FieldHasher<BYTE32, 2> for DigestHasher<D> {
+    fn hash(&self, nodes: [BYTE32; 2]) -> Result<BYTE32> {
+        let mut h = D::new();
+        for n in nodes {
+            Digest::update(&mut h, n);
+        }
+        Self::truncate(&h.finalize())
+    }
+}
+
+impl<D: Digest> BytesHasher<BYTE32> for DigestHasher<D> {
+    fn hash_bytes(&self, bytes: &[u8]) -> Result<BYTE32> {
+        Self::truncate(&D::digest(bytes))
+    }
+}
+
+/// The hash functions `DynHasher` can dispatch to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum HashAlgo {
+    Sha256,
+    /// Requires the `keccak` feature.
+    Keccak256,
+    /// Poseidon over the Pasta base field. Inputs must be canonical little
+    /// endian encodings of field elements. Requires the `poseidon` feature.
+    Poseidon,
+}
+
+/// A hasher over 32-byte elements whose hash function is chosen at runtime.
+#[derive(Clone, Copy, Debug)]
+pub struct DynHasher {
+    pub algo: HashAlgo,
+}
+
+impl DynHasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        DynHasher { algo }
+    }
+}
+
+impl FieldHasher<BYTE32, 2> for DynHasher {
+    fn hash(&self, nodes: [BYTE32; 2]) -> Result<BYTE32> {
+        match self.algo {
+            HashAlgo::Sha256 => Sha256::new().hash(nodes),
+            #[cfg(feature = "keccak")]
+            HashAlgo::Keccak256 => {
+                let mut h = Keccak256::new();
+                for n in nodes {
+                    Update::update(&mut h, &n);
+                }
+                Ok(h.finalize().into())
+            }
+            #[cfg(not(feature = "keccak"))]
+            HashAlgo::Keccak256 => bail!("keccak hashing requires the keccak feature"),
+            #[cfg(feature = "poseidon")]
+            HashAlgo::Poseidon => {
+                use crate::poseidon::{bytes_to_felt, felt_to_bytes, Fp, Poseidon};
+
+                let felts = [bytes_to_felt(&nodes[0])?, bytes_to_felt(&nodes[1])?];
+                let h = Poseidon::<Fp, 2>::new();
+                Ok(felt_to_bytes(&h.hash(felts)?))
+            }
+            #[cfg(not(feature = "poseidon"))]
+            HashAlgo::Poseidon => bail!("poseidon hashing requires the poseidon feature"),
+        }
+    }
+}
+
+impl BytesHasher<BYTE32> for DynHasher {
+    fn hash_bytes(&self, bytes: &[u8]) -> Result<BYTE32> {
+        match self.algo {
+            HashAlgo::Sha256 => Sha256::new().hash_bytes(bytes),
+            #[cfg(feature = "keccak")]
+            HashAlgo::Keccak256 => Ok(Keccak256::digest(bytes).into()),
+            #[cfg(not(feature = "keccak"))]
+            HashAlgo::Keccak256 => bail!("keccak hashing requires the keccak feature"),
+            HashAlgo::Poseidon => bail!("poseidon does not hash bytes"),
+        }
+    }
+}
+
+/// A 64-byte field element.
+///
+/// serde and `Default` are only implemented for arrays of up to 32 elements,
+/// so `[u8; 64]` is wrapped to satisfy the `FieldExt` bounds. It is serialized
+/// as its two 32-byte halves.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Bytes64(pub [u8; 64]);
+
+impl Default for Bytes64 {
+    fn default() -> Self {
+        Bytes64([0; 64])
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Bytes64 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut lo = [0; 32];
+        let mut hi = [0; 32];
+        lo.copy_from_slice(&self.0[..32]);
+        hi.copy_from_slice(&self.0[32..]);
+        (lo, hi).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Bytes64 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (lo, hi) = <([u8; 32], [u8; 32])>::deserialize(deserializer)?;
+        let mut s64 = [0; 64];
+        s64[..32].copy_from_slice(&lo);
+        s64[32..].copy_from_slice(&hi);
+        Ok(Bytes64(s64))
+    }
+}
+
+impl FieldExt for Bytes64 {}
+
+impl<const N: usize> FieldHasher<Bytes64, N> for Sha512 {
+    fn hash(&self, nodes: [Bytes64; N]) -> Result<Bytes64> {
+        let mut h = Sha512::new();
+        for n in nodes {
+            Update::update(&mut h, &n.0);
+        }
+        Ok(Bytes64(h.finalize().into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
     use super::*;
-    use rand::rngs::OsRng;
     use sha2::Sha256;
     use std::collections::BTreeMap;
 
+    /// The hasher, the leaves `[1; 32]` to `[9; 32]` at indices 0 to 8, and a
+    /// tree of height `N` holding them.
+    fn sample_tree<const N: usize>(
+    ) -> (Sha256, Vec<[u8; 32]>, SparseMerkleTree<[u8; 32], Sha256, N>) {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..10).map(|n| [n; 32]).collect();
+        let tree = SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        (h, leaves, tree)
+    }
+
+    #[test]
+    fn merkleput() {
+        let mut leaves = vec![];
+        for n in 0..10 {
+            let mut s = [0; 32];
+            s[0] = n;
+            leaves.push(s);
+        }
+        let h = Sha256::new();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let mut l1 = [0; 32];
+        l1[0] = 222;
+
+        let mut map = BTreeMap::new();
+        map.insert(2, l1);
+        tree.insert_batch(&map, &h).unwrap();
+        let p = tree.generate_membership_path(5).unwrap();
+        dbg!(&p);
+    }
+
+    #[test]
+    fn absent_leaves() {
+        let mut leaves = vec![];
+        for n in 0..6 {
+            let mut s = [0; 32];
+            s[0] = n + 1;
+            leaves.push(s);
+        }
+        let h = Sha256::new();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let pt = tree.batch_prove_with_absent(&[5], &[6]);
+        assert_eq!(pt.absent, vec![6]);
+        pt.verify(&h).unwrap();
+
+        // Index 4 holds a value, so it cannot be witnessed as empty.
+        let pt = tree.batch_prove_with_absent(&[5], &[4]);
+        assert!(pt.verify(&h).is_err());
+    }
+
+    #[test]
+    fn len_tracks_leaves() {
+        let h = Sha256::new();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&BTreeMap::new(), &h, [0; 32]).unwrap();
+        assert_eq!(tree.capacity(), 1 << 32);
+        assert!(tree.is_empty());
+
+        let mut map = BTreeMap::new();
+        for n in 0..4u32 {
+            let mut s = [0; 32];
+            s[0] = n as u8 + 1;
+            map.insert(n * 7, s);
+        }
+        tree.insert_batch(&map, &h).unwrap();
+        assert_eq!(tree.len(), 4);
+        let root = tree.root();
+
+        map.clear();
+        map.insert(100, [9; 32]);
+        tree.insert_batch(&map, &h).unwrap();
+        assert_eq!(tree.len(), 5);
+
+        tree.delete_leaf(100, &h).unwrap();
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.root(), root);
+
+        tree.delete_leaf(0, &h).unwrap();
+        assert_eq!(tree.len(), 3);
+        assert!(!tree.is_empty());
+    }
+
+    #[test]
+    fn empty_tree() {
+        let h = Sha256::new();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&BTreeMap::new(), &h, [0; 32]).unwrap();
+        let chain: heapless::Vec<[u8; 32], 32> = gen_empty_hashes(&h, [0; 32]).unwrap();
+        let empty_root = h.hash([chain[31], chain[31]]).unwrap();
+        assert_eq!(tree.root(), empty_root);
+
+        assert!(tree.generate_membership_proof(12).unwrap().verify(&h).unwrap());
+        tree.batch_prove_with_absent(&[], &[0, 12]).verify(&h).unwrap();
+
+        let mut map = BTreeMap::new();
+        map.insert(3, [3; 32]);
+        tree.insert_batch(&map, &h).unwrap();
+        assert_ne!(tree.root(), empty_root);
+        tree.delete_leaf(3, &h).unwrap();
+        assert_eq!(tree.root(), empty_root);
+
+        let err = SparseMerkleTree::<[u8; 32], Sha256, 0>::new(&BTreeMap::new(), &h, [0; 32])
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::TreeTooSmall {
+                required: 1,
+                available: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn tree_too_small() {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..=4).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 2> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        tree.batch_prove(&[0, 3]).verify(&h).unwrap();
+
+        let leaves: Vec<[u8; 32]> = (1..=5).map(|n| [n; 32]).collect();
+        let err = SparseMerkleTree::<[u8; 32], Sha256, 2>::new_sequential(&leaves, &h, [0; 32])
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::TreeTooSmall {
+                required: 3,
+                available: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn sparse_high_index() {
+        let h = Sha256::new();
+        let mut map = BTreeMap::new();
+        map.insert(3_000_000_000, [9; 32]);
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+        let proof = tree.generate_membership_proof(3_000_000_000).unwrap();
+        assert_eq!(proof.leaf, [9; 32]);
+        assert!(proof.verify(&h).unwrap());
+
+        // A single leaf at 1000 needs a tree of height 10.
+        let mut map = BTreeMap::new();
+        map.insert(1000, [9; 32]);
+        let err = SparseMerkleTree::<[u8; 32], Sha256, 8>::new(&map, &h, [0; 32])
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::TreeTooSmall {
+                required: 10,
+                available: 8
+            })
+        ));
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 10> =
+            SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+        assert!(tree.generate_membership_proof(1000).unwrap().verify(&h).unwrap());
+    }
+
+    #[test]
+    fn incremental_inserts() {
+        let h = Sha256::new();
+        let mut map = BTreeMap::new();
+        for n in 0..20u32 {
+            map.insert(n * 3, [n as u8 + 1; 32]);
+        }
+        let batched: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&BTreeMap::new(), &h, [0; 32]).unwrap();
+        let empty_root = tree.root();
+        for (i, leaf) in &map {
+            tree.insert(*i as u64, *leaf).unwrap();
+        }
+        assert!(tree.is_dirty());
+        assert_eq!(tree.root(), empty_root);
+
+        tree.flush(&h).unwrap();
+        assert!(!tree.is_dirty());
+        assert_eq!(tree.root(), batched.root());
+        assert_eq!(tree.tree, batched.tree);
+    }
+
+    #[test]
+    fn path_node_indices() {
+        let mut leaves = vec![];
+        for n in 0..10 {
+            let mut s = [0; 32];
+            s[0] = n + 1;
+            leaves.push(s);
+        }
+        let h = Sha256::new();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        for leaf in [0, 5, 9, 1000] {
+            let path = tree.generate_membership_path(leaf).unwrap();
+            let indices = path.node_indices(leaf).unwrap();
+            assert_eq!(indices.len(), 32);
+            assert_eq!(indices[31], (1, 2));
+
+            let pt = tree.batch_prove(&[leaf]);
+            for (level, ((l, r), (lh, rh))) in indices.iter().zip(&path.path).enumerate() {
+                let empty = &tree.empty_hashes[level];
+                assert_eq!(pt.tree.get(l).unwrap_or(empty), lh);
+                assert_eq!(pt.tree.get(r).unwrap_or(empty), rh);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_path() {
+        let h = Sha256::new();
+        let mut map = BTreeMap::new();
+        map.insert(77, [7; 32]);
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+
+        let path = tree.generate_membership_path(77).unwrap();
+        let compressed = path.compress(77, tree.empty_hashes()).unwrap();
+        assert_eq!(compressed.bitmap, 0);
+        assert!(compressed.siblings.is_empty());
+        #[cfg(feature = "serde")]
+        assert!(
+            serde_json::to_vec(&compressed).unwrap().len() * 10
+                < serde_json::to_vec(&path).unwrap().len()
+        );
+
+        let decompressed = compressed
+            .decompress(&[7; 32], tree.empty_hashes(), &h)
+            .unwrap();
+        assert_eq!(decompressed.path, path.path);
+        assert!(decompressed
+            .check_membership(&tree.root(), &[7; 32], &h)
+            .unwrap());
+
+        let leaves: Vec<[u8; 32]> = (1..12).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        for index in [0, 5, 10] {
+            let path = tree.generate_membership_path(index).unwrap();
+            let compressed = path.compress(index, tree.empty_hashes()).unwrap();
+            assert_eq!(compressed.bitmap >> 4, 0);
+            let decompressed = compressed
+                .decompress(&leaves[index as usize], tree.empty_hashes(), &h)
+                .unwrap();
+            assert_eq!(decompressed.path, path.path);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn canonical_serialization() {
+        let mut leaves = vec![];
+        for n in 0..10 {
+            let mut s = [0; 32];
+            s[0] = n + 1;
+            leaves.push(s);
+        }
+        let h = Sha256::new();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let a = tree.batch_prove(&[2, 7, 4]);
+        let mut b = tree.batch_prove(&[7, 4, 2]);
+        assert_eq!(
+            serde_json::to_vec(&a).unwrap(),
+            serde_json::to_vec(&b).unwrap()
+        );
+
+        b.leaves = vec![4, 2, 7, 2];
+        assert_ne!(
+            serde_json::to_vec(&a).unwrap(),
+            serde_json::to_vec(&b).unwrap()
+        );
+        b.canonicalize();
+        assert_eq!(
+            serde_json::to_vec(&a).unwrap(),
+            serde_json::to_vec(&b).unwrap()
+        );
+    }
+
+    #[test]
+    fn membership_64_bytes() {
+        let h = Sha512::new();
+        let leaves: Vec<Bytes64> = (1..8).map(|n| Bytes64([n; 64])).collect();
+        let tree: SparseMerkleTree<Bytes64, Sha512, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, Bytes64::default()).unwrap();
+
+        let proof = tree.generate_membership_proof(4).unwrap();
+        assert_eq!(proof.leaf, leaves[4]);
+        assert!(proof.verify(&h).unwrap());
+
+        #[cfg(feature = "serde")]
+        {
+            let s = serde_json::to_vec(&proof).unwrap();
+            let back: Proof<Bytes64, 32> = serde_json::from_slice(&s).unwrap();
+            assert!(back.verify(&h).unwrap());
+        }
+
+        tree.batch_prove(&[0, 4, 6, 100]).verify(&h).unwrap();
+    }
+
+    #[test]
+    fn proof_root() {
+        let (h, _, tree) = sample_tree::<32>();
+
+        let mut proof = tree.generate_membership_proof(3).unwrap();
+        assert_eq!(proof.verify_root(&h).unwrap(), tree.root());
+        assert!(proof.verify(&h).unwrap());
+
+        proof.root = [0xaa; 32];
+        let err = proof.verify(&h).unwrap_err();
+        match err.downcast_ref::<MerkleError>() {
+            Some(MerkleError::RootMismatch { computed, expected }) => {
+                assert_eq!(*computed, format!("{:?}", tree.root()));
+                assert_eq!(*expected, format!("{:?}", [0xaa; 32]));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn value_preimage() {
+        let h = Sha256::new();
+        let mut leaves: Vec<[u8; 32]> = (1..10).map(|n| [n; 32]).collect();
+        leaves[4] = h.hash_bytes(b"opening").unwrap();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let proof = tree.prove_value_preimage(4, b"opening", &h).unwrap();
+        assert!(proof.verify(&h).unwrap());
+
+        let err = tree.prove_value_preimage(4, b"guess", &h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::InvalidLeaf)
+        ));
+    }
+
+    #[test]
+    fn batch_update_proof() {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..40).map(|n| [n; 32]).collect();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let old_root = tree.root();
+
+        let mut updates = BTreeMap::new();
+        for (n, index) in [0u64, 7, 8, 31, 5000].iter().enumerate() {
+            updates.insert(*index, [100 + n as u8; 32]);
+        }
+        let proof = tree.update_batch(&updates, &h).unwrap();
+        assert_eq!(proof.old_root, old_root);
+        assert_eq!(proof.new_root, tree.root());
+        assert_eq!(proof.old_leaves[&5000], [0; 32]);
+        proof.verify(&h).unwrap();
+
+        let mut forged = tree.update_batch(&updates, &h).unwrap();
+        assert_eq!(forged.old_root, forged.new_root);
+        forged.new_leaves.insert(7, [1; 32]);
+        assert!(forged.verify(&h).is_err());
+    }
+
+    #[test]
+    fn batch_update_out_of_range() {
+        let (h, _, mut tree) = sample_tree::<8>();
+        let before = tree.clone();
+
+        for bad in [300, u64::MAX] {
+            let updates = BTreeMap::from([(3, [100; 32]), (bad, [101; 32])]);
+            let err = tree.update_batch(&updates, &h).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<MerkleError>(),
+                Some(MerkleError::IndexOutOfRange { index, capacity: 256 }) if *index == bad
+            ));
+        }
+        tree.flush(&h).unwrap();
+        assert_eq!(tree.root(), before.root());
+        assert!(tree.diff(&before).is_empty());
+    }
+
+    #[test]
+    fn prove_out_of_range() {
+        let (_, _, tree) = sample_tree::<8>();
+        let path = tree.generate_membership_path(1).unwrap();
+
+        for bad in [256, u64::MAX] {
+            let errs = [
+                tree.generate_membership_path(bad).err().unwrap(),
+                tree.generate_membership_proof(bad).err().unwrap(),
+                tree.generate_membership_proofs(&[1, bad]).err().unwrap(),
+                tree.clone().freeze().generate_membership_proof(bad).err().unwrap(),
+                path.node_indices(bad).err().unwrap(),
+            ];
+            for err in errs {
+                assert!(matches!(
+                    err.downcast_ref::<MerkleError>(),
+                    Some(MerkleError::IndexOutOfRange { index, capacity: 256 }) if *index == bad
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_leaves() {
+        let (h, _, tree) = sample_tree::<32>();
+
+        let mut pt = tree.batch_prove(&[5, 5]);
+        assert_eq!(pt.leaves, vec![5]);
+        pt.verify(&h).unwrap();
+
+        pt.leaves = vec![5, 2, 5];
+        let err = pt.verify(&h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::DuplicateLeaf { index: 5 })
+        ));
+    }
+
+    #[test]
+    fn partial_tree_bytes() {
+        use rand::{Rng, SeedableRng};
+
+        let (h, _, tree) = sample_tree::<32>();
+        let pt = tree.batch_prove_with_absent(&[1, 4], &[20]);
+
+        let bytes = pt.to_bytes();
+        let back = PartialTree::<BYTE32, 32>::from_bytes(&bytes).unwrap();
+        assert_eq!(back.to_bytes(), bytes);
+        back.verify(&h).unwrap();
+
+        for len in 0..bytes.len() {
+            assert!(PartialTree::<BYTE32, 32>::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(PartialTree::<BYTE32, 32>::from_bytes(&longer).is_err());
+
+        // Corrupted buffers may decode, but must not panic.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for _ in 0..1000 {
+            let mut corrupted = bytes.clone();
+            let i = rng.gen_range(0..corrupted.len());
+            corrupted[i] = rng.gen();
+            let _ = PartialTree::<BYTE32, 32>::from_bytes(&corrupted);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "keccak")]
+    fn runtime_hasher() {
+        let leaves: Vec<[u8; 32]> = (1..10).map(|n| [n; 32]).collect();
+        let sha = DynHasher::new(HashAlgo::Sha256);
+        let keccak = DynHasher::new(HashAlgo::Keccak256);
+        let sha_tree: SparseMerkleTree<[u8; 32], DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &sha, [0; 32]).unwrap();
+        let keccak_tree: SparseMerkleTree<[u8; 32], DynHasher, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &keccak, [0; 32]).unwrap();
+        assert_ne!(sha_tree.root(), keccak_tree.root());
+
+        let h = Sha256::new();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        assert_eq!(sha_tree.root(), tree.root());
+
+        keccak_tree.batch_prove(&[2, 3]).verify(&keccak).unwrap();
+        assert!(keccak_tree.batch_prove(&[2, 3]).verify(&sha).is_err());
+    }
+
+    #[test]
+    fn partial_tree_leaf_values() {
+        let (h, leaves, tree) = sample_tree::<32>();
+
+        let pt = tree.batch_prove(&[8, 2, 50]);
+        pt.verify(&h).unwrap();
+        assert_eq!(
+            pt.leaf_values(),
+            vec![(2, leaves[2]), (8, leaves[8]), (50, [0; 32])]
+        );
+    }
+
+    #[test]
+    fn structured_errors() {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..8).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 4> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let err = |pt: &PartialTree<[u8; 32], 4>| {
+            let e = pt.verify(&h).unwrap_err();
+            e.downcast::<MerkleError>().unwrap()
+        };
+
+        let mut pt = tree.batch_prove(&[1]);
+        pt.leaves.push(16);
+        assert!(matches!(
+            err(&pt),
+            MerkleError::IndexOutOfRange {
+                index: 16,
+                capacity: 16
+            }
+        ));
+
+        let pt = tree.batch_prove_with_absent(&[1], &[3]);
+        assert!(matches!(err(&pt), MerkleError::NonEmptyLeaf { index: 3 }));
+
+        let mut pt = tree.batch_prove(&[1]);
+        pt.tree.insert(convert_index_to_last_level(1, 4), [0xff; 32]);
+        let parent = parent(convert_index_to_last_level(1, 4)).unwrap();
+        assert!(matches!(err(&pt), MerkleError::NodeMismatch { index } if index == parent));
+
+        let mut pt = tree.batch_prove(&[1]);
+        pt.root = [0xff; 32];
+        assert!(matches!(err(&pt), MerkleError::RootMismatch { .. }));
+
+        let empty = Path::<[u8; 32], 4> {
+            path: heapless::Vec::new(),
+        };
+        let e = empty.calculate_root(&[1; 32], &h).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MerkleError>(),
+            Some(MerkleError::InvalidPathNodes)
+        ));
+    }
+
+    #[test]
+    fn tampered_empty_hashes() {
+        let (h, _, tree) = sample_tree::<32>();
+
+        let mut pt = tree.batch_prove(&[3]);
+        pt.empty_hashes[9] = [0xee; 32];
+        let err = pt.verify(&h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::EmptyHashMismatch { level: 9 })
+        ));
+    }
+
+    #[test]
+    fn verify_many_trees() {
+        let h = Sha256::new();
+        let mut trees = vec![];
+        let mut roots = vec![];
+        for t in 0..4u8 {
+            let leaves: Vec<[u8; 32]> = (0..5).map(|n| [t * 10 + n; 32]).collect();
+            let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+                SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+            roots.push(tree.root());
+            trees.push(tree.batch_prove(&[1, 3]));
+        }
+        assert_eq!(verify_many(&trees, &h).unwrap(), roots);
+
+        let index = convert_index_to_last_level(3, 32);
+        trees[2].tree.insert(index, [0xff; 32]);
+        let err = verify_many(&trees, &h).unwrap_err();
+        assert_eq!(err.to_string(), "partial tree 2 failed to verify");
+    }
+
+    #[test]
+    fn clone_snapshot() {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..6).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let root = tree.root();
+
+        let mut speculative = tree.clone();
+        assert_eq!(speculative.root(), root);
+        let mut updates = BTreeMap::new();
+        updates.insert(2, [0xaa; 32]);
+        updates.insert(9, [0xbb; 32]);
+        speculative.insert_batch(&updates, &h).unwrap();
+
+        assert_ne!(speculative.root(), root);
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.len(), 5);
+        assert!(tree.generate_membership_proof(2).unwrap().verify(&h).unwrap());
+    }
+
+    #[test]
+    fn recompute_after_map_edit() {
+        let h = Sha256::new();
+        let mut leaves: Vec<[u8; 32]> = (1..6).map(|n| [n; 32]).collect();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        leaves[3] = [0xcc; 32];
+        tree.tree.insert(convert_index_to_last_level(3, 32), leaves[3]);
+        tree.tree.remove(&convert_index_to_last_level(4, 32));
+        leaves.pop();
+        tree.recompute_from(&[3, 4], &h).unwrap();
+
+        let clean: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        assert_eq!(tree.root(), clean.root());
+        assert!(tree.generate_membership_proof(3).unwrap().verify(&h).unwrap());
+    }
+
+    #[test]
+    fn malformed_partial_trees() {
+        use rand::{Rng, SeedableRng};
+
+        let (h, _, tree) = sample_tree::<8>();
+        let valid = || tree.batch_prove_with_absent(&[1, 4], &[20]);
+        valid().validate_structure().unwrap();
+
+        let mut pt = valid();
+        pt.tree.insert(1 << 9, [1; 32]);
+        let err = pt.verify(&h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::NodeOutOfRange { index: 512 })
+        ));
+
+        let mut pt = valid();
+        pt.empty_hashes.truncate(5);
+        let err = pt.validate_structure().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::InvalidEmptyHashes { len: 5, height: 8 })
+        ));
+
+        let mut pt = valid();
+        pt.absent.push(u64::MAX);
+        assert!(pt.validate_structure().is_err());
+
+        // Random corruptions must be reported as errors, not panics.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+        for _ in 0..1000 {
+            let mut pt = valid();
+            match rng.gen_range(0..5) {
+                0 => {
+                    pt.tree.insert(rng.gen(), rng.gen());
+                }
+                1 => pt.leaves.push(rng.gen_range(0..1 << 9)),
+                2 => pt.absent.push(rng.gen_range(0..1 << 9)),
+                3 => pt.empty_hashes.truncate(rng.gen_range(0..8)),
+                _ => {
+                    let keys: Vec<u64> = pt.tree.keys().cloned().collect();
+                    let key = keys[rng.gen_range(0..keys.len())];
+                    pt.tree.insert(key, rng.gen());
+                }
+            }
+            let _ = pt.verify(&h);
+        }
+    }
+
+    #[test]
+    fn sibling_proof() {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..8).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        let proof = tree.prove_siblings(2).unwrap();
+        assert_eq!((proof.left, proof.right), (leaves[2], leaves[3]));
+        assert_eq!(proof.parent, h.hash([leaves[2], leaves[3]]).unwrap());
+        assert!(proof.verify(&h).unwrap());
+
+        // The last leaf's sibling is empty.
+        let proof = tree.prove_siblings(6).unwrap();
+        assert_eq!(proof.right, [0; 32]);
+        assert!(proof.verify(&h).unwrap());
+
+        // 3 and 4 are adjacent, but have different parents.
+        let err = tree.prove_siblings(3).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::NotLeftChild { index: 3 })
+        ));
+        let mut proof = tree.prove_siblings(2).unwrap();
+        proof.index = 4;
+        assert!(proof.verify(&h).is_err());
+        let mut proof = tree.prove_siblings(2).unwrap();
+        proof.right = leaves[4];
+        assert!(proof.verify(&h).is_err());
+    }
+
+    #[test]
+    fn batch_insert_is_atomic() {
+        let h = Sha256::new();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new_sequential(&[[1; 32], [2; 32]], &h, [0; 32]).unwrap();
+        let root = tree.root();
+
+        let mut batch = BTreeMap::new();
+        batch.insert(5, [5; 32]);
+        batch.insert(256, [6; 32]);
+        let err = tree.try_insert_batch(&batch, &h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::IndexOutOfRange {
+                index: 256,
+                capacity: 256
+            })
+        ));
+        assert!(tree.insert_batch(&batch, &h).is_err());
+        assert_eq!(tree.len(), 2);
+        assert!(!tree.is_dirty());
+        assert_eq!(tree.root(), root);
+
+        assert!(tree.insert(256, [6; 32]).is_err());
+        batch.remove(&256);
+        tree.try_insert_batch(&batch, &h).unwrap();
+        assert!(tree.generate_membership_proof(5).unwrap().verify(&h).unwrap());
+    }
+
     #[test]
-    fn merkleput() {
-        let mut leaves = vec![];
-        for n in 0..10 {
-            let mut s = [0; 32];
-            s[0] = n;
-            leaves.push(s);
+    fn chunked_insert() {
+        let h = Sha256::new();
+        let leaves: BTreeMap<u32, [u8; 32]> =
+            (0..40u32).map(|i| (i * 5, [i as u8 + 1; 32])).collect();
+        let expected: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&leaves, &h, [0; 32]).unwrap();
+
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&BTreeMap::new(), &h, [0; 32]).unwrap();
+        let pairs: Vec<(u64, [u8; 32])> = leaves.iter().map(|(i, l)| (*i as u64, *l)).collect();
+        let mut roots = vec![];
+        for chunks in pairs.chunks(16) {
+            let chunks = chunks.chunks(7).map(|c| c.iter().cloned().collect());
+            tree.insert_chunked(chunks, &h).unwrap();
+            roots.push(tree.root());
+        }
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.tree, expected.tree);
+        assert_eq!(tree.len(), 40);
+
+        // The root read between calls is the root of the leaves so far.
+        let prefix: BTreeMap<u32, [u8; 32]> =
+            leaves.iter().take(16).map(|(i, l)| (*i, *l)).collect();
+        let partial: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&prefix, &h, [0; 32]).unwrap();
+        assert_eq!(roots[0], partial.root());
+
+        let bad = [BTreeMap::from([(1, [1; 32])]), BTreeMap::from([(2, [2; 32]), (256, [3; 32])])];
+        assert!(tree.insert_chunked(bad.into_iter(), &h).is_err());
+        assert!(tree.is_present(1));
+        assert!(!tree.is_present(2));
+        assert!(!tree.is_dirty());
+    }
+
+    #[test]
+    fn tree_diff() {
+        let (h, _, a) = sample_tree::<8>();
+        let mut b = a.clone();
+        assert!(a.diff(&b).is_empty());
+
+        b.insert(3, [0xaa; 32]).unwrap();
+        b.insert(200, [0xbb; 32]).unwrap();
+        b.flush(&h).unwrap();
+        assert_eq!(a.diff(&b), vec![3, 200]);
+        assert_eq!(b.diff(&a), vec![3, 200]);
+
+        // An explicitly stored empty leaf holds the same value as an unset one.
+        b.insert(3, [4; 32]).unwrap();
+        b.insert(200, [0; 32]).unwrap();
+        b.flush(&h).unwrap();
+        assert!(a.diff(&b).is_empty());
+    }
+
+    #[test]
+    fn sibling_at_level() {
+        let (_, _, tree) = sample_tree::<16>();
+
+        for index in [0, 3, 8, 100] {
+            let path = tree.generate_membership_path(index).unwrap();
+            for (level, (left, right)) in path.path.iter().enumerate() {
+                let expected = if (index >> level) & 1 == 1 { left } else { right };
+                assert_eq!(tree.sibling_at(index, level).unwrap(), *expected);
+            }
+        }
+        assert_eq!(tree.sibling_at(8, 0).unwrap(), tree.empty_hashes()[0]);
+
+        let err = tree.sibling_at(0, 16).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::LevelOutOfRange {
+                level: 16,
+                height: 16
+            })
+        ));
+        assert!(tree.sibling_at(1 << 16, 0).is_err());
+    }
+
+    #[test]
+    fn partial_tree_compute_root() {
+        let (h, _, tree) = sample_tree::<32>();
+
+        let mut pt = tree.batch_prove_with_absent(&[0, 5, 8], &[40]);
+        assert_eq!(pt.compute_root(&h).unwrap(), tree.root());
+
+        // The computed root doesn't depend on the claimed one.
+        pt.root = [0xee; 32];
+        assert_eq!(pt.compute_root(&h).unwrap(), tree.root());
+        assert!(pt.verify(&h).is_err());
+
+        let other = tree.batch_prove(&[3]);
+        assert_eq!(other.compute_root(&h).unwrap(), pt.compute_root(&h).unwrap());
+    }
+
+    #[test]
+    fn prune_partial_tree() {
+        let (h, leaves, tree) = sample_tree::<32>();
+
+        let mut pt = tree.batch_prove_with_absent(&[0, 5, 8], &[40]);
+        pt.leaves = vec![5];
+        pt.tree.insert(convert_index_to_last_level(1000, 32), [3; 32]);
+        pt.verify(&h).unwrap();
+        let before = pt.to_bytes().len();
+
+        pt.prune();
+        assert!(pt.to_bytes().len() < before);
+        pt.verify(&h).unwrap();
+        assert_eq!(pt.leaf_values(), vec![(5, leaves[5])]);
+
+        // Pruning a minimal tree changes nothing.
+        let nodes = pt.tree.clone();
+        pt.prune();
+        assert_eq!(pt.tree, nodes);
+        for (i, node) in &nodes {
+            assert_ne!(*node, tree.empty_hashes()[node_level(*i, 32)]);
+        }
+
+        // Nodes out of range, e.g. from a malformed input, are dropped.
+        for bad in [u64::MAX, (1 << 33) - 1] {
+            pt.tree.insert(bad, [3; 32]);
+        }
+        pt.prune();
+        assert_eq!(pt.tree, nodes);
+    }
+
+    #[test]
+    fn collect_mismatches() {
+        let (h, _, tree) = sample_tree::<8>();
+        let mut pt = tree.batch_prove(&[0, 8]);
+        pt.verify_collect(&h).unwrap();
+
+        // The parents of leaves 0 and 8, on separate paths.
+        let (a, b) = (parent(255).unwrap(), parent(263).unwrap());
+        pt.tree.insert(a, [0xaa; 32]);
+        pt.tree.insert(b, [0xbb; 32]);
+        let Err(VerifyError::Mismatches(mismatches)) = pt.verify_collect(&h) else {
+            panic!("expected mismatches");
+        };
+        assert_eq!(
+            mismatches,
+            vec![(b, tree.tree[&b], [0xbb; 32]), (a, tree.tree[&a], [0xaa; 32])]
+        );
+        let err = pt.verify(&h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::NodeMismatch { index }) if *index == b
+        ));
+
+        pt.root = [0; 32];
+        let Err(VerifyError::Mismatches(mismatches)) = pt.verify_collect(&h) else {
+            panic!("expected mismatches");
+        };
+        assert_eq!(mismatches.len(), 3);
+        assert_eq!(mismatches[2], (0, tree.root(), [0; 32]));
+
+        // Only a wrong root is still an error.
+        let mut pt = tree.batch_prove(&[0, 8]);
+        pt.root = [0; 32];
+        assert!(matches!(
+            pt.verify_collect(&h),
+            Err(VerifyError::Mismatches(m)) if m == vec![(0, tree.root(), [0; 32])]
+        ));
+        pt.empty_hashes.truncate(3);
+        assert!(matches!(pt.verify_collect(&h), Err(VerifyError::Structure(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "notzk")]
+    fn verify_against_trusted_root() {
+        let (h, leaves, tree) = sample_tree::<8>();
+        let other: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new_sequential(&leaves[1..], &h, [0; 32]).unwrap();
+
+        let pt = tree.batch_prove(&[0, 5]);
+        pt.verify(&h).unwrap();
+        pt.verify_against(&tree.root(), &h).unwrap();
+
+        // Consistent with its own root, but not the one the verifier trusts.
+        let err = pt.verify_against(&other.root(), &h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::RootMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn light_verification() {
+        let (h, _, tree) = sample_tree::<32>();
+        let pt = tree.batch_prove_with_absent(&[0, 5], &[40]);
+        pt.verify(&h).unwrap();
+        pt.verify_light(&h).unwrap();
+
+        // Without the interior nodes on the paths, only light verification
+        // passes.
+        let mut light = tree.batch_prove_with_absent(&[0, 5], &[40]);
+        let mut on_path = BTreeSet::new();
+        for i in [0, 5, 40] {
+            let mut node = convert_index_to_last_level(i, 32);
+            while let Some(parent) = parent(node) {
+                on_path.insert(parent);
+                node = parent;
+            }
+        }
+        light.tree.retain(|i, _| !on_path.contains(i));
+        assert!(light.tree.len() < pt.tree.len());
+        light.verify_light(&h).unwrap();
+        assert!(light.verify(&h).is_err());
+
+        // Both reject a wrong leaf or root.
+        let mut bad = tree.batch_prove(&[5]);
+        bad.tree.insert(convert_index_to_last_level(5, 32), [0xaa; 32]);
+        assert!(bad.verify_light(&h).is_err());
+        assert!(bad.verify(&h).is_err());
+        light.root = [0; 32];
+        assert!(light.verify_light(&h).is_err());
+    }
+
+    #[test]
+    fn extract_subtree() {
+        let h = Sha256::new();
+        let mut map = BTreeMap::new();
+        for i in [0u32, 3, 17, 18, 31, 200] {
+            map.insert(i, [i as u8 + 1; 32]);
+        }
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+
+        // The node at level 4 above leaves 16..32.
+        let mut node = convert_index_to_last_level(16, 8);
+        for _ in 0..4 {
+            node = parent(node).unwrap();
+        }
+        let sub: SparseMerkleTree<[u8; 32], Sha256, 4> = tree.subtree(node).unwrap();
+        assert_eq!(sub.root(), tree.tree[&node]);
+        assert_eq!(sub.len(), 3);
+        let proof = sub.generate_membership_proof(2).unwrap();
+        assert_eq!(proof.leaf, map[&18]);
+        assert!(proof.verify(&h).unwrap());
+
+        // An empty subtree has the empty root of its height.
+        let empty_node = parent(parent(convert_index_to_last_level(100, 8)).unwrap()).unwrap();
+        let sub: SparseMerkleTree<[u8; 32], Sha256, 2> = tree.subtree(empty_node).unwrap();
+        let empty: SparseMerkleTree<[u8; 32], Sha256, 2> =
+            SparseMerkleTree::new(&BTreeMap::new(), &h, [0; 32]).unwrap();
+        assert_eq!(sub.root(), empty.root());
+
+        let whole: SparseMerkleTree<[u8; 32], Sha256, 8> = tree.subtree(0).unwrap();
+        assert_eq!(whole.root(), tree.root());
+        assert!(tree.subtree::<3>(node).is_err());
+        assert!(tree.subtree::<0>(convert_index_to_last_level(3, 8)).is_err());
+    }
+
+    #[test]
+    fn frozen_get_unset() {
+        let h = Sha256::new();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&[(1, [1; 32]), (2, [0; 32])].into(), &h, [0; 32]).unwrap();
+        tree.insert(3, [3; 32]).unwrap();
+        tree.delete_leaf(3, &h).unwrap();
+
+        let frozen = tree.freeze();
+        assert_eq!(frozen.get(1), Some(&[1; 32]));
+        // Set to the empty leaf, as opposed to never set or deleted.
+        assert_eq!(frozen.get(2), Some(&[0; 32]));
+        assert_eq!(frozen.get(3), None);
+        assert_eq!(frozen.get(4), None);
+        assert_eq!(frozen.get(256), None);
+    }
+
+    #[test]
+    fn frozen_snapshot_across_threads() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..20).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let frozen = tree.freeze();
+        assert_send_sync(&frozen);
+        assert_eq!(frozen.get(4), Some(&leaves[4]));
+        assert_eq!(frozen.get(400), None);
+        assert_eq!(frozen.get(19), None);
+
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let frozen = frozen.clone();
+                std::thread::spawn(move || {
+                    let h = Sha256::new();
+                    for i in (t..19).step_by(4) {
+                        let proof = frozen.generate_membership_proof(i).unwrap();
+                        assert_eq!(proof.root, frozen.root());
+                        assert!(proof.verify(&h).unwrap());
+                    }
+                    frozen.batch_prove(&[t, t + 10]).verify(&h).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn forest_shares_empty_hashes() {
+        let h = Sha256::new();
+        let mut forest: Forest<[u8; 32], Sha256, 32> = Forest::new(&h, [0; 32]).unwrap();
+        let mut expected = vec![];
+        for epoch in 0..3u64 {
+            let mut leaves = BTreeMap::new();
+            leaves.insert(epoch as u32, [epoch as u8 + 1; 32]);
+            leaves.insert(100, [0xaa; 32]);
+            forest.insert(epoch, &leaves, &h).unwrap();
+            let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+                SparseMerkleTree::new(&leaves, &h, [0; 32]).unwrap();
+            expected.push(tree.root());
+        }
+
+        let mut more = BTreeMap::new();
+        more.insert(7, [7; 32]);
+        forest.insert(1, &more, &h).unwrap();
+        let mut all = more.clone();
+        all.insert(1, [2; 32]);
+        all.insert(100, [0xaa; 32]);
+        expected[1] = SparseMerkleTree::<[u8; 32], Sha256, 32>::new(&all, &h, [0; 32])
+            .unwrap()
+            .root();
+
+        for (epoch, root) in expected.iter().enumerate() {
+            let tree = forest.tree(epoch as u64).unwrap();
+            assert_eq!(tree.root(), *root);
+            assert!(Arc::ptr_eq(&tree.empty_hashes, &forest.empty_hashes));
+            assert!(tree.generate_membership_proof(100).unwrap().verify(&h).unwrap());
         }
+        assert_eq!(expected.iter().collect::<BTreeSet<_>>().len(), 3);
+        assert!(forest.tree(3).is_none());
+    }
+
+    #[test]
+    fn present_empty_leaf() {
         let h = Sha256::new();
+        let leaves = BTreeMap::from([(0, [1; 32]), (5, [0; 32])]);
+        let plain: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&leaves, &h, [0; 32]).unwrap();
+        assert!(plain.is_present(5));
+        // Without presence binding, proofs don't claim what the root can't
+        // tell.
+        assert!(!plain.generate_membership_proof(5).unwrap().present);
+        assert!(plain.batch_prove(&[0, 5]).present.is_empty());
+
         let mut tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::with_presence(&leaves, &h, [0; 32]).unwrap();
+        assert!(tree.binds_presence());
+        assert_ne!(tree.root(), plain.root());
+
+        let set = tree.generate_membership_proof(5).unwrap();
+        let unset = tree.generate_membership_proof(6).unwrap();
+        assert_eq!(set.leaf, unset.leaf);
+        assert!(set.present && !unset.present);
+        for (index, proof) in [(5, &set), (6, &unset)] {
+            assert!(proof.verify(&h).unwrap());
+            assert!(proof.verify_with_presence(index, &h, &[0; 32]).unwrap());
+            let mut flipped = proof.clone();
+            flipped.present = !flipped.present;
+            assert!(flipped.verify_with_presence(index, &h, &[0; 32]).is_err());
+        }
+        // Claiming the node as the value of a leaf that is not present.
+        let mut hidden = set.clone();
+        hidden.present = false;
+        hidden.leaf = present_leaf(&[0; 32], &h).unwrap();
+        hidden.verify(&h).unwrap();
+        assert!(hidden.verify_with_presence(5, &h, &[0; 32]).is_err());
+
+        let pt = tree.batch_prove_with_absent(&[0, 5, 6], &[7]);
+        assert_eq!(pt.present, vec![0, 5]);
+        pt.verify_with_presence(&h).unwrap();
+        let back = PartialTree::<BYTE32, 32>::from_bytes(&pt.to_bytes()).unwrap();
+        assert_eq!(back.present, pt.present);
+        back.verify_with_presence(&h).unwrap();
+        for present in [vec![0], vec![0, 5, 6]] {
+            let mut forged = PartialTree::<BYTE32, 32>::from_bytes(&pt.to_bytes()).unwrap();
+            forged.present = present;
+            assert!(forged.verify(&h).is_err());
+        }
+        let mut hidden = PartialTree::<BYTE32, 32>::from_bytes(&pt.to_bytes()).unwrap();
+        hidden.present = vec![0];
+        let node = present_leaf(&[0; 32], &h).unwrap();
+        hidden.tree.insert(convert_index_to_last_level(5, 32), node);
+        hidden.verify(&h).unwrap();
+        assert!(matches!(
+            hidden.verify_with_presence(&h).unwrap_err().downcast_ref::<MerkleError>(),
+            Some(MerkleError::NonEmptyLeaf { index: 5 })
+        ));
+
+        let mut pt = pt;
+        pt.leaves = vec![5, 6];
+        pt.prune();
+        assert_eq!(pt.present, vec![5]);
+        pt.verify_with_presence(&h).unwrap();
+
+        assert!(tree.subtree::<31>(1).is_err());
+        assert!(tree.update_batch(&BTreeMap::from([(1, [2; 32])]), &h).is_err());
+        assert!(tree.prove_siblings(0).is_err());
+
+        tree.delete_leaf(5, &h).unwrap();
+        assert!(!tree.is_present(5));
+        assert!(!tree.generate_membership_proof(5).unwrap().present);
+        let only_first: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::with_presence(&[(0, [1; 32])].into(), &h, [0; 32]).unwrap();
+        assert_eq!(tree.root(), only_first.root());
+        assert_eq!(tree.diff(&only_first), Vec::<u64>::new());
+    }
+
+    /// Counts the hashes computed through it.
+    struct CountingHasher {
+        calls: std::cell::Cell<usize>,
+    }
+
+    impl FieldHasher<[u8; 32], 2> for CountingHasher {
+        fn hash(&self, nodes: [[u8; 32]; 2]) -> Result<[u8; 32]> {
+            self.calls.set(self.calls.get() + 1);
+            Sha256::new().hash(nodes)
+        }
+    }
+
+    #[test]
+    fn verify_hashes_shared_parents_once() {
+        let h = CountingHasher {
+            calls: std::cell::Cell::new(0),
+        };
+        let leaves: Vec<[u8; 32]> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], CountingHasher, 8> =
             SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
-        let mut l1 = [0; 32];
-        l1[0] = 222;
 
+        for proven in [&[0, 1][..], &[2, 3, 4], &[0, 1, 2, 3, 8]] {
+            let pt = tree.batch_prove(proven);
+            let mut interior = BTreeSet::new();
+            for i in proven {
+                let mut node = convert_index_to_last_level(*i, 8);
+                while let Some(parent) = parent(node) {
+                    interior.insert(parent);
+                    node = parent;
+                }
+            }
+
+            // The empty hash chain, then each interior node once.
+            h.calls.set(0);
+            pt.verify(&h).unwrap();
+            assert_eq!(h.calls.get(), 8 + interior.len());
+        }
+    }
+
+    #[test]
+    fn batch_prove_stats() {
+        let h = CountingHasher {
+            calls: std::cell::Cell::new(0),
+        };
+        let leaves: Vec<[u8; 32]> = (1..10).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], CountingHasher, 8> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+
+        for proven in [&[][..], &[0], &[2, 3, 4], &[0, 1, 2, 3, 8, 200]] {
+            let (pt, stats) = tree.batch_prove_with_stats(proven);
+            assert_eq!(pt.tree, tree.batch_prove(proven).tree);
+            assert_eq!(stats.nodes, pt.tree.len());
+            assert_eq!(stats.size, pt.to_bytes().len());
+
+            h.calls.set(0);
+            pt.verify(&h).unwrap();
+            assert_eq!(stats.hashes, h.calls.get());
+        }
+
+        // Each present leaf costs one more hash.
+        let present: BTreeMap<u32, [u8; 32]> = (0..9).map(|n| (n, [n as u8; 32])).collect();
+        let tree: SparseMerkleTree<[u8; 32], CountingHasher, 8> =
+            SparseMerkleTree::with_presence(&present, &h, [0; 32]).unwrap();
+        let (pt, stats) = tree.batch_prove_with_stats(&[0, 1, 2, 200]);
+        assert_eq!(stats.size, pt.to_bytes().len());
+        h.calls.set(0);
+        pt.verify(&h).unwrap();
+        assert_eq!(stats.hashes, h.calls.get());
+    }
+
+    #[test]
+    fn empty_region() {
+        let h = Sha256::new();
+        let region = EmptyRegion {
+            start: 64,
+            end: 128,
+            empty_leaf: [9; 32],
+        };
+        let leaves: BTreeMap<u32, [u8; 32]> = [(1, [1; 32]), (70, [2; 32]), (200, [3; 32])].into();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::with_empty_region(&leaves, &h, [0; 32], region.clone()).unwrap();
+        let plain: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&leaves, &h, [0; 32]).unwrap();
+        assert_ne!(tree.root(), plain.root());
+        assert_eq!(tree.empty_region(), Some(&region));
+
+        // Setting every leaf of the region to its empty leaf gives a plain tree
+        // with those leaves set.
+        let mut filled = leaves.clone();
+        filled.extend((64..128).filter(|i| *i != 70).map(|i| (i, [9; 32])));
+        let filled: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new(&filled, &h, [0; 32]).unwrap();
+        assert_eq!(tree.root(), filled.root());
+
+        for (index, leaf) in [(1, [1; 32]), (70, [2; 32]), (71, [9; 32]), (5, [0; 32])] {
+            let proof = tree.generate_membership_proof(index).unwrap();
+            assert_eq!(proof.leaf, leaf);
+            assert_eq!(proof.calculate_root(&h).unwrap(), tree.root());
+        }
+
+        let pt = tree.batch_prove_with_absent(&[1, 70], &[5, 71, 127]);
+        assert_eq!(pt.region, Some(region.clone()));
+        pt.verify(&h).unwrap();
+        let (stats_pt, stats) = tree.batch_prove_with_stats(&[1, 70]);
+        assert_eq!(stats.size, stats_pt.to_bytes().len());
+        let counting = CountingHasher {
+            calls: std::cell::Cell::new(0),
+        };
+        stats_pt.verify(&counting).unwrap();
+        assert_eq!(stats.hashes, counting.calls.get());
+        let bytes = pt.to_bytes();
+        let decoded = PartialTree::<[u8; 32], 8>::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.region, Some(region.clone()));
+        decoded.verify(&h).unwrap();
+
+        let mut plain_pt = PartialTree::<[u8; 32], 8>::from_bytes(&bytes).unwrap();
+        plain_pt.region = None;
+        assert!(plain_pt.verify(&h).is_err());
+
+        // A leaf of the region holding the tree's empty leaf is set, pruning
+        // must keep it.
+        let mut tree = tree;
+        tree.insert_batch(&[(72, [0; 32])].into(), &h).unwrap();
+        let mut pt = tree.batch_prove(&[72]);
+        pt.prune();
+        assert_eq!(pt.leaf_values(), vec![(72, [0; 32])]);
+        pt.verify(&h).unwrap();
+
+        for (start, end) in [(3, 7), (64, 64), (0, 256), (96, 160), (256, 512)] {
+            let err = SparseMerkleTree::<[u8; 32], Sha256, 8>::with_empty_region(
+                &BTreeMap::new(),
+                &h,
+                [0; 32],
+                EmptyRegion {
+                    start,
+                    end,
+                    empty_leaf: [9; 32],
+                },
+            )
+            .err()
+            .unwrap();
+            assert!(matches!(
+                err.downcast_ref::<MerkleError>(),
+                Some(MerkleError::InvalidRegion { start: s, end: e }) if (*s, *e) == (start, end)
+            ));
+        }
+    }
+
+    #[test]
+    fn partial_tree_summary() {
+        let (_, _, tree) = sample_tree::<8>();
+
+        let pt = tree.batch_prove_with_absent(&[0, 2, 3, 5, 8, 9], &[200]);
+        let summary = pt.to_string();
+        assert!(summary.contains(&format!("nodes: {},", pt.tree.len())));
+        assert!(summary.contains("leaves: 6,"));
+        assert!(summary.contains("absent: 1,"));
+        assert!(summary.contains(&format!("root: 0x{},", hex::encode(tree.root()))));
+        assert!(summary.contains("first leaves: [0, 2, 3, 5] and 2 more"));
+
+        let summary = tree.batch_prove(&[4]).to_string();
+        assert!(summary.ends_with("first leaves: [4] }"));
+    }
+
+    #[test]
+    fn truncated_empty_hashes() {
+        let (h, _, tree) = sample_tree::<32>();
+
+        for len in [0, 1, 31] {
+            let mut pt = tree.batch_prove(&[1, 4]);
+            pt.empty_hashes.truncate(len);
+            for err in [pt.verify(&h).unwrap_err(), pt.verify_light(&h).unwrap_err()] {
+                assert!(matches!(
+                    err.downcast_ref::<MerkleError>(),
+                    Some(MerkleError::InvalidEmptyHashes { len: l, height: 32 }) if *l == len
+                ));
+            }
+            assert!(pt.compute_root(&h).is_err());
+        }
+    }
+
+    #[test]
+    fn reverse_lookup() {
+        let h = Sha256::new();
         let mut map = BTreeMap::new();
-        map.insert(2, l1);
-        let p = tree.insert_batch(&map, &h);
-        let p = tree.generate_membership_path(5);
-        dbg!(&p);
+        map.insert(3, [1; 32]);
+        map.insert(70_000, [0xab; 32]);
+        map.insert(90_000, [0xab; 32]);
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+
+        assert_eq!(tree.find(&[0xab; 32]), Some(70_000));
+        let proof = tree.prove_value(&[0xab; 32]).unwrap().unwrap();
+        assert_eq!(proof.leaf, [0xab; 32]);
+        assert!(proof.verify(&h).unwrap());
+        assert_eq!(proof.path.path, tree.generate_membership_path(70_000).unwrap().path);
+
+        assert_eq!(tree.find(&[2; 32]), None);
+        assert!(tree.prove_value(&[0; 32]).unwrap().is_none());
+    }
+
+    /// Hashes `[a, b]` around other calls, and checks it always gives the same
+    /// value, which differs from the hash of `[b, a]`.
+    fn is_pure<F: FieldExt, H: FieldHasher<F, 2>>(h: &H, a: F, b: F) -> bool {
+        let first = h.hash([a, b]).unwrap();
+        let swapped = h.hash([b, a]).unwrap();
+        let again = h.hash([a, b]).unwrap();
+        let _ = h.hash([first, swapped]).unwrap();
+        first == again && first == h.hash([a, b]).unwrap() && first != swapped
+    }
+
+    /// A hasher keeping a digest between calls, which it must reset.
+    struct Running {
+        digest: std::cell::RefCell<Sha256>,
+        reset: bool,
+    }
+
+    impl FieldHasher<[u8; 32], 2> for Running {
+        fn hash(&self, nodes: [[u8; 32]; 2]) -> Result<[u8; 32]> {
+            let mut digest = self.digest.borrow_mut();
+            for n in nodes {
+                Update::update(&mut *digest, &n);
+            }
+            if self.reset {
+                Ok(digest.finalize_reset().into())
+            } else {
+                Ok(digest.clone().finalize().into())
+            }
+        }
+    }
+
+    #[test]
+    fn hashers_are_pure() {
+        let (a, b) = ([1; 32], [2; 32]);
+        assert!(is_pure(&Sha256::new(), a, b));
+        assert!(is_pure(&DynHasher::new(HashAlgo::Sha256), a, b));
+        #[cfg(feature = "keccak")]
+        assert!(is_pure(&DynHasher::new(HashAlgo::Keccak256), a, b));
+        assert!(is_pure(&Sha512::new(), Bytes64([1; 64]), Bytes64([2; 64])));
+        #[cfg(feature = "poseidon")]
+        {
+            use crate::poseidon::{Felt, Fp, Poseidon};
+            let (a, b) = (Felt(Fp::from(1)), Felt(Fp::from(2)));
+            assert!(is_pure(&Poseidon::<Fp, 2>::new(), a, b));
+        }
+
+        let running = Running {
+            digest: std::cell::RefCell::new(Sha256::new()),
+            reset: true,
+        };
+        assert!(is_pure(&running, a, b));
+        assert_eq!(running.hash([a, b]).unwrap(), Sha256::new().hash([a, b]).unwrap());
+
+        // Leaking the digest into the next call is caught.
+        let leaky = Running {
+            digest: std::cell::RefCell::new(Sha256::new()),
+            reset: false,
+        };
+        assert!(!is_pure(&leaky, a, b));
+    }
+
+    #[test]
+    fn digest_hashers() {
+        let leaves: Vec<[u8; 32]> = (1..10).map(|n| [n; 32]).collect();
+        fn root<H: FieldHasher<BYTE32, 2>>(h: &H, leaves: &[BYTE32]) -> BYTE32 {
+            let tree: SparseMerkleTree<[u8; 32], H, 32> =
+                SparseMerkleTree::new_sequential(leaves, h, [0; 32]).unwrap();
+            assert!(tree.batch_prove(&[0, 4]).verify(h).is_ok());
+            tree.root()
+        }
+
+        let sha256 = root(&DigestHasher::<Sha256>::new(), &leaves);
+        assert_eq!(sha256, root(&Sha256::new(), &leaves));
+        assert_ne!(sha256, root(&DigestHasher::<Sha512>::new(), &leaves));
+        #[cfg(feature = "keccak")]
+        {
+            let roots = [
+                sha256,
+                root(&DigestHasher::<Sha512>::new(), &leaves),
+                root(&DigestHasher::<Keccak256>::new(), &leaves),
+                root(&DigestHasher::<sha3::Sha3_256>::new(), &leaves),
+            ];
+            assert_eq!(roots.iter().collect::<BTreeSet<_>>().len(), roots.len());
+            assert_eq!(
+                DigestHasher::<Keccak256>::new().hash_bytes(b"abc").unwrap(),
+                DynHasher::new(HashAlgo::Keccak256).hash_bytes(b"abc").unwrap()
+            );
+        }
+
+        let short = DigestHasher::<sha2::Sha224>::new();
+        assert!(short.hash([[1; 32], [2; 32]]).is_err());
+        let tree = SparseMerkleTree::<[u8; 32], _, 8>::new_sequential(&leaves, &short, [0; 32]);
+        assert!(tree.is_err());
+    }
+
+    #[test]
+    fn proof_fixed_bytes() {
+        let (h, leaves, tree) = sample_tree::<32>();
+        let proof = tree.generate_membership_proof(6).unwrap();
+
+        let mut bytes = [0; Proof::<BYTE32, 32>::FIXED_SIZE];
+        assert_eq!(bytes.len(), 32 * 66);
+        proof.to_fixed_bytes(tree.empty_hashes(), &mut bytes).unwrap();
+        assert_eq!(&bytes[..32], &proof.path.path[0].0);
+        assert_eq!(&bytes[bytes.len() - 32..], &leaves[6]);
+
+        let back = Proof::<BYTE32, 32>::from_fixed_bytes(&bytes).unwrap();
+        assert_eq!(back.path.path, proof.path.path);
+        assert_eq!((back.root, back.leaf), (tree.root(), leaves[6]));
+        assert!(back.verify(&h).unwrap());
+
+        assert!(Proof::<BYTE32, 32>::from_fixed_bytes(&bytes[1..]).is_err());
+        assert!(proof.to_fixed_bytes(tree.empty_hashes(), &mut bytes[1..]).is_err());
+
+        // The top two levels are padded with pairs of empty hashes.
+        let mut short = proof.clone();
+        short.path.path.truncate(30);
+        short.to_fixed_bytes(tree.empty_hashes(), &mut bytes).unwrap();
+        let back = Proof::<BYTE32, 32>::from_fixed_bytes(&bytes).unwrap();
+        assert_eq!(back.path.path[..30], short.path.path[..]);
+        for level in [30, 31] {
+            let empty = tree.empty_hashes()[level];
+            assert_eq!(back.path.path[level], (empty, empty));
+        }
+        assert_eq!((back.root, back.leaf), (short.root, short.leaf));
+        assert!(short.to_fixed_bytes(&tree.empty_hashes()[..31], &mut bytes).is_err());
+        // A full path needs no empty hashes.
+        proof.to_fixed_bytes(&[], &mut bytes).unwrap();
+    }
+
+    #[test]
+    fn tallest_tree() {
+        let h = Sha256::new();
+        let mut tree: SparseMerkleTree<[u8; 32], Sha256, 63> =
+            SparseMerkleTree::new_sequential(&[[1; 32]], &h, [0; 32]).unwrap();
+        assert_eq!(tree.capacity(), 1 << 63);
+
+        let last = (1u64 << 63) - 1;
+        assert_eq!(convert_index_to_last_level(last, 63), u64::MAX - 1);
+        tree.insert(last, [2; 32]).unwrap();
+        tree.flush(&h).unwrap();
+        assert!(tree.insert(last + 1, [3; 32]).is_err());
+
+        let proof = tree.generate_membership_proof(last).unwrap();
+        assert_eq!(proof.leaf, [2; 32]);
+        assert!(proof.verify(&h).unwrap());
+        let pt = tree.batch_prove_with_absent(&[0, last], &[last - 1]);
+        pt.verify(&h).unwrap();
+        assert_eq!(pt.compute_root(&h).unwrap(), tree.root());
+    }
+
+    #[test]
+    fn explicit_leaf_position() {
+        let h = Sha256::new();
+        let (a, b) = ([1; 32], [2; 32]);
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+            SparseMerkleTree::new_sequential(&[a, a, b], &h, [0; 32]).unwrap();
+        let proof = tree.generate_membership_proof(0).unwrap();
+        assert_eq!(proof.path.path[0].0, proof.path.path[0].1);
+        assert_eq!(proof.calculate_root(&h).unwrap(), tree.root());
+
+        // Both sides of the first pair hold the leaf, so it is at 0 or 1.
+        for index in [0, 1] {
+            let root = proof.path.calculate_root_at(&a, index, &h).unwrap();
+            assert_eq!(root, tree.root());
+        }
+        let err = proof.path.calculate_root_at(&a, 2, &h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::InvalidPathNodes)
+        ));
+        let err = proof.path.calculate_root_at(&b, 0, &h).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::InvalidLeaf)
+        ));
+        assert!(proof.path.calculate_root_at(&a, 1 << 8, &h).is_err());
+    }
+
+    #[test]
+    fn from_sorted_pairs() {
+        let h = Sha256::new();
+        let pairs: Vec<(u64, [u8; 32])> = [0, 1, 3, 4, 9, 200, 254]
+            .iter()
+            .map(|i| (*i, [*i as u8 + 1; 32]))
+            .collect();
+
+        for n in 0..=pairs.len() {
+            let map: BTreeMap<u32, [u8; 32]> =
+                pairs[..n].iter().map(|(i, l)| (*i as u32, *l)).collect();
+            let expected: SparseMerkleTree<[u8; 32], Sha256, 8> =
+                SparseMerkleTree::new(&map, &h, [0; 32]).unwrap();
+            let tree: SparseMerkleTree<[u8; 32], Sha256, 8> =
+                SparseMerkleTree::from_sorted_pairs(&pairs[..n], &h, [0; 32]).unwrap();
+            assert_eq!(tree.root(), expected.root());
+            assert_eq!(tree.tree, expected.tree);
+            assert!(pairs[..n].iter().all(|(i, _)| tree.is_present(*i)));
+        }
+    }
+
+    #[test]
+    fn from_unsorted_pairs() {
+        let h = Sha256::new();
+        let from = |pairs: &[(u64, [u8; 32])]| {
+            SparseMerkleTree::<[u8; 32], Sha256, 8>::from_sorted_pairs(pairs, &h, [0; 32])
+                .map(|_| ())
+                .unwrap_err()
+        };
+
+        let err = from(&[(1, [1; 32]), (5, [2; 32]), (3, [3; 32])]);
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::UnsortedLeaves { index: 3 })
+        ));
+        let err = from(&[(1, [1; 32]), (1, [2; 32])]);
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::DuplicateLeaf { index: 1 })
+        ));
+        let err = from(&[(1, [1; 32]), (256, [2; 32])]);
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::IndexOutOfRange { index: 256, .. })
+        ));
+    }
+
+    #[test]
+    fn capacity_exceeded() {
+        let (_, _, tree) = sample_tree::<8>();
+
+        // Eight empty hashes don't fit in a partial tree of height 4.
+        let bytes = tree.batch_prove(&[1]).to_bytes();
+        let err = PartialTree::<[u8; 32], 4>::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MerkleError>(),
+            Some(MerkleError::CapacityExceeded { capacity: 4 })
+        ));
+
+        let mut full: heapless::Vec<u64, 2> = heapless::Vec::new();
+        try_push(&mut full, 1).unwrap();
+        try_push(&mut full, 2).unwrap();
+        assert!(try_push(&mut full, 3).is_err());
+        assert_eq!(full, [1, 2]);
+    }
+
+    #[test]
+    fn batch_membership_proofs() {
+        let (h, _, tree) = sample_tree::<32>();
+
+        let indices = [8, 0, 1, 5, 1, 300];
+        let proofs = tree.generate_membership_proofs(&indices).unwrap();
+        assert_eq!(proofs.len(), indices.len());
+        for (index, proof) in indices.iter().zip(&proofs) {
+            let single = tree.generate_membership_proof(*index).unwrap();
+            assert_eq!(proof.leaf, single.leaf);
+            assert_eq!(proof.path.path, single.path.path);
+            assert_eq!(proof.root, tree.root());
+            assert!(proof.verify(&h).unwrap());
+        }
+    }
+
+    #[test]
+    fn verify_in_place_matches() {
+        let h = Sha256::new();
+        let leaves: Vec<[u8; 32]> = (1..8).map(|n| [n; 32]).collect();
+        let tree: SparseMerkleTree<[u8; 32], Sha256, 32> =
+            SparseMerkleTree::new_sequential(&leaves, &h, [0; 32]).unwrap();
+        let root = tree.root();
+
+        // The same result, or the same error.
+        let same = |path: &Path<[u8; 32], 32>, root: &[u8; 32], leaf: &[u8; 32]| {
+            let in_place = path.verify_in_place(root, leaf, &h).map_err(|e| e.to_string());
+            let checked = path.check_membership(root, leaf, &h).map_err(|e| e.to_string());
+            assert_eq!(in_place, checked);
+        };
+        for (i, leaf) in leaves.iter().enumerate() {
+            let path = tree.generate_membership_path(i as u64).unwrap();
+            assert!(path.verify_in_place(&root, leaf, &h).unwrap());
+            same(&path, &root, leaf);
+            same(&path, &[9; 32], leaf);
+            same(&path, &root, &[9; 32]);
+
+            let mut broken = path.clone();
+            broken.path[5].0 = [9; 32];
+            broken.path[5].1 = [9; 32];
+            same(&broken, &root, leaf);
+        }
+        same(&Path { path: heapless::Vec::new() }, &root, &leaves[0]);
+
+        let path = tree.generate_membership_path(3).unwrap();
+        let before = alloc_counter::count();
+        assert!(path.verify_in_place(&root, &leaves[3], &h).unwrap());
+        assert_eq!(alloc_counter::count(), before);
+    }
+
+    /// Counts the allocations made by the current thread, so that tests running
+    /// in parallel don't interfere.
+    mod alloc_counter {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        thread_local! {
+            static COUNT: Cell<usize> = const { Cell::new(0) };
+        }
+
+        struct Counting;
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = COUNT.try_with(|c| c.set(c.get() + 1));
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        #[global_allocator]
+        static ALLOC: Counting = Counting;
+
+        pub fn count() -> usize {
+            COUNT.with(|c| c.get())
+        }
     }
 }